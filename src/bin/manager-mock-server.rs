@@ -0,0 +1,308 @@
+//! Mock manager server for local runs and demos, no network needed.
+//! Listens on host and ports from cert.json, serves data, file and stream ports from script dir:
+//!   point_config.json         GetPointConfigAnsw
+//!   configs/<id>.json         GetProgramConfigAnsw
+//!   poll/*.json               queued PollAnsw, one taken per poll in name order (drop files while running)
+//!   update/*.json             queued GetUpdateDataAnsw, one taken per request
+//!   register.json             RegisterData answered on Register
+//!   files/<pid>_build.tar.zst, files/<pid>_asset.tar.zst - downloads, resume supported
+//!   files/<pid>_build.patch   patch for any base, offered by queued BuildPatch in update/
+//!   recordings/               uploaded recordings
+//!   streams/<id>.raw          data received on stream port
+//!   received.jsonl            every data port request as JSON line
+//!   legacy                    if exists, Hello not answered like old server
+//! Usage: manager-mock-server <dir> [cert.json]
+
+#[allow(dead_code, clippy::all)]
+#[path = "../data_types.rs"]
+mod data_types;
+#[allow(dead_code, clippy::all)]
+#[path = "../utils/frame.rs"]
+mod frame;
+#[allow(dead_code, clippy::all)]
+#[path = "../utils/compress.rs"]
+mod compress;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use serde::{de::DeserializeOwned, Serialize};
+use ring::digest::{digest, SHA256};
+
+use data_types::Cert;
+use data_types::data_server::*;
+use data_types::{file_server, stream_api};
+use frame::{read_frame, write_frame};
+
+/// See utils::framed.
+const FRAMED_MAGIC: &[u8;4] = b"SIMF";
+const FRAMED_VERSION: u16 = 1;
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+pub fn err(e: &str) -> Error {
+	Error::other(e)
+}
+
+fn encode<T: Serialize>(val: &T) -> Vec<u8> {
+	rmp_serde::encode::to_vec(val).unwrap()
+}
+
+fn decode<T: DeserializeOwned, R: Read>(r: R) -> Result<T, Error> {
+	rmp_serde::decode::from_read(r).map_err(|e| err(&e.to_string()))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+	let data = fs::read(path).ok()?;
+	match serde_json::from_slice(&data) {
+		Ok(val) => Some(val),
+		Err(e) => {
+			println!("[MOCK] invalid {:?}: {}", path, e);
+			None
+		}
+	}
+}
+
+struct Mock {
+	dir: PathBuf,
+	received: Mutex<File>
+}
+
+impl Mock {
+	/// Oldest queued answer in subdir, file removed.
+	fn take_queued<T: DeserializeOwned>(&self, sub: &str) -> Option<T> {
+		let mut files: Vec<PathBuf> = fs::read_dir(self.dir.join(sub)).ok()?
+			.flatten()
+			.map(|e| e.path())
+			.filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+			.collect();
+		files.sort();
+		let path = files.into_iter().next()?;
+		let val = read_json(&path);
+		let _ = fs::remove_file(&path);
+		println!("[MOCK] {:?} taken", path);
+		val
+	}
+
+	fn record(&self, req: &Request) {
+		if let Ok(line) = serde_json::to_string(req) {
+			let _ = writeln!(self.received.lock().unwrap(), "{}", line);
+		}
+	}
+
+	fn handle(&self, req: Request) -> Result<Vec<u8>, Error> {
+		let req = match req {
+			// signature not checked
+			Request::Signed(_, _, body) => decode(&body[..])?,
+			req => req
+		};
+		self.record(&req);
+		if matches!(req, Request::Hello(_, _)) && self.dir.join("legacy").exists() {
+			return Ok(Vec::new());
+		}
+		Ok(match req {
+			Request::Poll(_) => encode(&self.take_queued::<PollAnsw>("poll").unwrap_or(PollAnsw::Nothing)),
+			Request::GetUpdateData(_, _) => encode(&self.take_queued::<GetUpdateDataAnsw>("update").unwrap_or(GetUpdateDataAnsw::Nothing)),
+			Request::GetPointConfig(_) => {
+				let config: GetPointConfigAnsw = read_json(&self.dir.join("point_config.json")).ok_or_else(|| err("no point_config.json"))?;
+				encode(&config)
+			},
+			Request::GetProgramConfig(_, id) => {
+				let config: GetProgramConfigAnsw = read_json(&self.dir.join(format!("configs/{}.json", id))).ok_or_else(|| err("no program config"))?;
+				encode(&config)
+			},
+			Request::Register(name, firm) => {
+				let data = read_json(&self.dir.join("register.json")).unwrap_or(RegisterData {
+					id: 1,
+					name,
+					firm_id: 1,
+					firm_name: firm.unwrap_or_else(|| String::from("mock")),
+					token: vec![1, 2, 3, 4],
+					client_cert: None,
+					client_key: None
+				});
+				encode(&RegisterAnsw::Ok(data))
+			},
+			Request::AddBatch(_, items) => encode(&AddBatchAnsw(items.iter().map(|i| i.id).collect())),
+			Request::Hello(_, _) => encode(&Capabilities {
+				version: String::from("mock"),
+				revision: PROTOCOL_REVISION,
+				features: [
+					FEATURE_BATCH, FEATURE_COMPRESS, FEATURE_FRAMED, FEATURE_SIGNED, FEATURE_TOKEN_ROTATION, FEATURE_RESUME,
+					FEATURE_OFFLINE_REPORT, FEATURE_SHELL, FEATURE_TRANSFER, FEATURE_TUNNEL, FEATURE_RECORDING, FEATURE_DELTA
+				].iter().map(|f| f.to_string()).collect()
+			}),
+			_ => encode(&OkAnsw {})
+		})
+	}
+
+	/// Plain: one msgpack request (optionally compress framed) per connection, answer, close.
+	/// Framed: MAGIC, hello, then id + body frames until device closes.
+	fn data_conn(&self, mut tcp: TcpStream) -> Result<(), Error> {
+		let mut head = [0;4];
+		let len = tcp.peek(&mut head)?;
+		if len == 4 && &head == FRAMED_MAGIC {
+			tcp.read_exact(&mut head)?;
+			let _: FramedHello = decode(&read_frame(&mut tcp, MAX_FRAME_SIZE)?[..])?;
+			write_frame(&mut tcp, &encode(&FramedHelloAnsw::Ok(FRAMED_VERSION)))?;
+			loop {
+				let raw = match read_frame(&mut tcp, MAX_FRAME_SIZE) {
+					Ok(raw) if raw.len() >= 8 => raw,
+					Ok(_) => return Err(err("frame without request id")),
+					Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+					Err(e) => return Err(e)
+				};
+				let (body, framed) = unframe(&raw[8..])?;
+				let mut answ = raw[..8].to_vec();
+				answ.extend(reframe(self.handle(decode(&body[..])?)?, framed));
+				write_frame(&mut tcp, &answ)?;
+			}
+		}
+		let mut first = [0;2];
+		tcp.peek(&mut first)?;
+		let (req, framed): (Request, bool) = if first[0] == compress::MARKER {
+			tcp.read_exact(&mut first)?;
+			if first[1] & compress::FLAG_COMPRESSED != 0 {
+				// zstd frame and msgpack value are self delimited, device waits for answer
+				(decode(zstd::stream::read::Decoder::new(&tcp)?.single_frame())?, true)
+			} else {
+				(decode(&tcp)?, true)
+			}
+		} else {
+			(decode(&tcp)?, false)
+		};
+		tcp.write_all(&reframe(self.handle(req)?, framed))?;
+		tcp.shutdown(Shutdown::Both)
+	}
+
+	fn file_conn(&self, mut tcp: TcpStream) -> Result<(), Error> {
+		let mut first = [0;1];
+		tcp.peek(&mut first)?;
+		let (req, resume) = decode_file_request(first[0], &tcp)?;
+		let name = match req.res_type {
+			file_server::ResourceType::Build => format!("{}_build.tar.zst", req.point_program_id),
+			file_server::ResourceType::Asset => format!("{}_asset.tar.zst", req.point_program_id),
+			file_server::ResourceType::BuildPatch(_) => format!("{}_build.patch", req.point_program_id),
+			file_server::ResourceType::Recording(name, fsize) => {
+				let dir = self.dir.join("recordings");
+				fs::create_dir_all(&dir)?;
+				let fname = Path::new(&name).file_name().ok_or_else(|| err("invalid recording name"))?;
+				let mut data = Vec::new();
+				(&tcp).take(fsize).read_to_end(&mut data)?;
+				fs::write(dir.join(fname), &data)?;
+				println!("[MOCK] recording {} uploaded, {} B", name, data.len());
+				let answ = file_server::Answer {hash: digest(&SHA256, &data).as_ref().to_vec(), fsize: data.len() as u32, offset: 0};
+				tcp.write_all(&encode(&answ))?;
+				return tcp.shutdown(Shutdown::Both);
+			}
+		};
+		let data = fs::read(self.dir.join("files").join(&name))?;
+		let hash = digest(&SHA256, &data).as_ref().to_vec();
+		let offset = match resume {
+			Some((offset, part_hash)) if part_hash == hash && offset <= data.len() as u64 => offset,
+			_ => 0
+		};
+		println!("[MOCK] download {} from {}", name, offset);
+		tcp.write_all(&encode(&file_server::Answer {hash, fsize: data.len() as u32, offset}))?;
+		tcp.write_all(&data[offset as usize..])?;
+		tcp.shutdown(Shutdown::Both)
+	}
+
+	fn stream_conn(&self, tcp: TcpStream) -> Result<(), Error> {
+		// baseline request is 2 field array, anything else is extended one
+		let mut first = [0;1];
+		tcp.peek(&mut first)?;
+		let (req, compressed) = if first[0] == 0x92 {
+			(decode::<stream_api::Request, _>(&tcp)?, false)
+		} else {
+			match decode::<stream_api::ExtRequest, _>(&tcp)? {
+				stream_api::ExtRequest::Compressed(req) => (req, true),
+				stream_api::ExtRequest::Denied(req, reason) => {
+					println!("[MOCK] stream {} denied: {}", req.id, reason);
+					return Ok(());
+				}
+			}
+		};
+		let dir = self.dir.join("streams");
+		fs::create_dir_all(&dir)?;
+		let path = dir.join(format!("{}.raw", req.id));
+		println!("[MOCK] stream {} opened (initiator {}, compressed {})", req.id, req.initiator, compressed);
+		let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+		let len = std::io::copy(&mut &tcp, &mut file)?;
+		println!("[MOCK] stream {} closed, {} B", req.id, len);
+		Ok(())
+	}
+}
+
+/// Request and resume info: offset, hash of partial.
+type FileRequest = (file_server::Request, Option<(u64, Vec<u8>)>);
+
+/// Plain request is array, extended one is enum (map). Signature not checked.
+fn decode_file_request<R: Read>(first: u8, r: R) -> Result<FileRequest, Error> {
+	if first & 0xf0 == 0x90 {
+		return Ok((decode(r)?, None));
+	}
+	match decode(r)? {
+		file_server::ExtRequest::Signed(_, _, body) => decode_file_request(body.first().copied().unwrap_or(0), &body[..]),
+		file_server::ExtRequest::Resume(req, offset, hash) => Ok((req, Some((offset, hash))))
+	}
+}
+
+/// Body of compress framed message, plain returned as is.
+fn unframe(raw: &[u8]) -> Result<(Vec<u8>, bool), Error> {
+	match raw.first() {
+		Some(&compress::MARKER) if raw.len() >= 2 => {
+			if raw[1] & compress::FLAG_COMPRESSED != 0 {
+				Ok((zstd::bulk::decompress(&raw[2..], MAX_FRAME_SIZE)?, true))
+			} else {
+				Ok((raw[2..].to_vec(), true))
+			}
+		},
+		_ => Ok((raw.to_vec(), false))
+	}
+}
+
+/// Answer framed (uncompressed) if request was, so device knows it may compress.
+fn reframe(answ: Vec<u8>, framed: bool) -> Vec<u8> {
+	if !framed {
+		return answ;
+	}
+	let mut raw = vec![compress::MARKER, compress::FLAG_ACCEPT];
+	raw.extend(answ);
+	raw
+}
+
+fn serve(host: &str, port: u16, mock: Arc<Mock>, conn: fn(&Mock, TcpStream) -> Result<(), Error>, kind: &'static str) -> Result<(), Error> {
+	let listener = TcpListener::bind((host, port))?;
+	println!("[MOCK] {} port on {}:{}", kind, host, port);
+	thread::spawn(move || {
+		for tcp in listener.incoming().flatten() {
+			let mock = mock.clone();
+			thread::spawn(move || {
+				if let Err(e) = conn(&mock, tcp) {
+					println!("[MOCK] {} connection fail: {:?}", kind, e);
+				}
+			});
+		}
+	});
+	Ok(())
+}
+
+fn main() -> Result<(), Error> {
+	let args: Vec<String> = std::env::args().collect();
+	let dir = PathBuf::from(args.get(1).ok_or_else(|| err("usage: manager-mock-server <dir> [cert.json]"))?);
+	let cert_path = args.get(2).map(String::as_str).unwrap_or("./cert.json");
+	let cert: Cert = serde_json::from_slice(&fs::read(cert_path)?).map_err(|e| err(&e.to_string()))?;
+	fs::create_dir_all(&dir)?;
+	let received = OpenOptions::new().create(true).append(true).open(dir.join("received.jsonl"))?;
+	let mock = Arc::new(Mock {dir, received: Mutex::new(received)});
+
+	serve(&cert.host, cert.data_port, mock.clone(), Mock::data_conn, "data")?;
+	serve(&cert.host, cert.file_port, mock.clone(), Mock::file_conn, "file")?;
+	serve(&cert.host, cert.stream_port, mock, Mock::stream_conn, "stream")?;
+	loop {
+		thread::park();
+	}
+}
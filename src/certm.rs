@@ -1,27 +1,159 @@
-/// Cert manager, read cert from disk, try registration if cert only base.
-/// Put Cert Resource. Reset Cert if receive corresponding PollEvent(NotReg).
+//! Cert manager, read cert from disk, try registration if cert only base.
+//! Put Cert Resource. Reset Cert and register again in background if receive corresponding PollEvent(NotReg).
+//! Rotate token on server request, new token saved as pending, confirmed, then promoted.
 
 use bevy_ecs::prelude::*;
 use std::io::Error;
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 use crate::data_types::Cert;
-use crate::data_types::AppState;
-use crate::data_types::AppStateCode;
 use crate::data_types::data_server::Auth;
 use crate::data_types::data_server::RegisterAnsw;
-use crate::events::NotReg;
+use crate::events::{NotReg, RotateToken};
+use crate::srvm::Server;
 use crate::utils;
 use utils::mos;
-use utils::siapi;
+use utils::siapi::{self, IntApi};
+use utils::tls::{self, TlsConnector};
+use utils::sign;
+use utils::proxy::Proxy;
 use crate::stages;
 
 const REGISTER_REQ_DELAY: Duration = Duration::from_millis(5000);
 
-fn sys_reset(ev: EventReader<NotReg>, mut state: ResMut<AppState>, cert: Res<Cert>) {
-    if !ev.is_empty() {
-        reset_cert(&cert).unwrap();
-        state.code = AppStateCode::Shutdown;
+#[derive(Resource, Default)]
+pub struct Registration {
+    handle: Option<JoinHandle<Cert>>
+}
+
+/// Point revoked or unknown to server - wipe auth and register again without restart.
+/// Pending token tried first, server may have dropped old one after confirming it before crash.
+/// Pending token kept on disk till registration done. Server not polled while auth is None.
+fn sys_reset(evr: EventReader<NotReg>, mut reg: ResMut<Registration>, mut cert: ResMut<Cert>, server: Res<Server>) {
+    if evr.is_empty() {
+        return;
+    }
+    evr.clear();
+    if reg.handle.is_some() || cert.auth.is_none() {
+        return;
+    }
+    if let Some(token) = cert.pending_token.clone() {
+        println!("[CERTM] old token not accepted, confirm pending one..");
+        if confirm(&mut cert, &server.api, token) {
+            cert.pending_token = None;
+            match mos::write_cert(&cert) {
+                Ok(()) => println!("[CERTM] token rotated"),
+                Err(e) => println!("[CERTM] fail to save rotated token: {:?}", e)
+            }
+            return;
+        }
+    }
+    println!("[CERTM] point not registered, register again..");
+    if let Err(e) = reset_cert(&cert) {
+        println!("[CERTM] fail to reset cert: {:?}", e);
+    }
+    cert.auth = None;
+    let base = cert.clone();
+    reg.handle = Some(thread::spawn(move || loop {
+        match register(base.clone()) {
+            Ok(new_cert) => return new_cert,
+            Err(e) => {
+                println!("[CERTM] fail to register: {:?}", e);
+                thread::sleep(REGISTER_REQ_DELAY);
+            }
+        }
+    }));
+}
+
+fn sys_registration(mut reg: ResMut<Registration>, mut cert: ResMut<Cert>, mut server: ResMut<Server>) {
+    match &reg.handle {
+        Some(h) if h.is_finished() => (),
+        _ => return
+    }
+    let new_cert = match reg.handle.take().unwrap().join() {
+        Ok(new_cert) => new_cert,
+        Err(_) => {
+            println!("[CERTM] registration thread panicked");
+            return;
+        }
+    };
+    if let Err(e) = mos::write_cert(&new_cert) {
+        println!("[CERTM] fail to save cert: {:?}", e);
+    }
+    if new_cert.tls.enabled && new_cert.tls.mutual {
+        // client certificate issued again
+        match TlsConnector::new(&new_cert.tls) {
+            Ok(tls) => server.api = server.api.clone().with_tls(tls),
+            Err(e) => println!("[CERTM] fail to load new client certificate: {:?}", e)
+        }
+    }
+    server.api.set_auth(new_cert.auth.clone().unwrap());
+    *cert = new_cert;
+    println!("[CERTM] registered again");
+}
+
+/// Save new token as pending, switch api to it and confirm it with server, then promote it in cert.
+/// Old token restored if confirmation failed, server keeps it until confirmed.
+/// Pending token left by crash confirmed again on start, so token confirmed but not saved is not lost,
+/// on NotReg it is left to sys_reset. New token sent by server accepted only over TLS.
+fn sys_rotate(
+    mut evr: EventReader<RotateToken>,
+    evr_not_reg: EventReader<NotReg>,
+    mut cert: ResMut<Cert>,
+    server: Res<Server>,
+    mut started: Local<bool>
+) {
+    let mut tokens: Vec<Vec<u8>> = Vec::new();
+    // server must be reachable, otherwise pending token would be dropped unconfirmed
+    if !*started && server.api.peer().is_some() {
+        *started = true;
+        if let (Some(token), true) = (&cert.pending_token, evr_not_reg.is_empty()) {
+            println!("[CERTM] pending token found, confirm it..");
+            tokens.push(token.clone());
+        }
+    }
+    let auth = match &cert.auth {
+        Some(auth) => auth.clone(),
+        None => return
+    };
+    for ev in evr.iter() {
+        match ev {
+            RotateToken::Derive(salt) => tokens.push(sign::derive_token(&auth.token, salt)),
+            RotateToken::New(_) if !cert.tls.enabled => println!("[CERTM] new token over plain connection rejected"),
+            RotateToken::New(token) => tokens.push(token.clone())
+        }
+    }
+    for token in tokens {
+        cert.pending_token = Some(token.clone());
+        if let Err(e) = mos::write_cert(&cert) {
+            println!("[CERTM] fail to save pending token, rotation skipped: {:?}", e);
+            cert.pending_token = None;
+            continue;
+        }
+        let rotated = confirm(&mut cert, &server.api, token);
+        cert.pending_token = None;
+        match mos::write_cert(&cert) {
+            Ok(()) if rotated => println!("[CERTM] token rotated"),
+            Ok(()) => (),
+            Err(e) => println!("[CERTM] fail to save rotated token: {:?}", e)
+        }
+    }
+}
+
+/// Switch api to token and confirm it with server, promote it in cert if confirmed, restore old one otherwise.
+fn confirm(cert: &mut Cert, api: &IntApi, token: Vec<u8>) -> bool {
+    let auth = cert.auth.clone().unwrap();
+    api.set_token(token.clone());
+    match api.confirm_token() {
+        Ok(()) => {
+            cert.auth = Some(Auth {id: auth.id, token});
+            true
+        },
+        Err(e) => {
+            println!("[CERTM] new token not confirmed, old one kept: {:?}", e);
+            api.set_token(auth.token);
+            false
+        }
     }
 }
 
@@ -43,30 +175,54 @@ pub fn init(world: &mut World, schedule: &mut Schedule) -> Result<(), Error> {
 
     println!("\t[CERTM] cert sucess loaded");
     world.insert_resource(cert);
+    world.init_resource::<Registration>();
     schedule.add_system_to_stage(stages::Core::Save, sys_reset);
+    schedule.add_system_to_stage(stages::Core::HandlePollEvents, sys_rotate);
+    schedule.add_system_to_stage(stages::Core::Main, sys_registration);
 
     Ok(())
 }
 
 fn register(cert: Cert) -> Result<Cert, Error> {
-    let point_name = match cert.name {
-        Some(name) => name,
+    let point_name = match &cert.name {
+        Some(name) => name.clone(),
         _ => mos::get_hostname()
     };
-    let api = siapi::IntApi::new(cert.host.clone(), cert.data_port, cert.file_port, 0, Vec::new());
+    // point may be revoked while main server down, fallbacks tried in turn like srvm does
+    let mut api = siapi::IntApi::new(cert.server_addr(), 0, Vec::new())
+        .with_fallback(cert.failover.hosts.clone());
+    if let Some(proxy) = Proxy::from_policy(&cert.proxy)? {
+        api = api.with_proxy(proxy);
+    }
+    if cert.tls.enabled {
+        api = api.with_tls(TlsConnector::new(&cert.tls)?);
+    }
+    let mut failures = 0;
     loop {
-        let answ = api.register(point_name.clone(), cert.firm_name.clone())?;
+        let answ = match api.register(point_name.clone(), cert.firm_name.clone()) {
+            Ok(answ) => answ,
+            Err(e) => {
+                failures += 1;
+                if failures > cert.failover.hosts.len() || !api.failover() {
+                    return Err(e);
+                }
+                println!("\t[CERTM] register fail: {:?}, try next server", e);
+                continue;
+            }
+        };
         match answ {
             RegisterAnsw::Ok(data) => {
+                if let (true, Some(crt), Some(key)) = (cert.tls.mutual, &data.client_cert, &data.client_key) {
+                    tls::save_identity(&cert.tls, crt, key)?;
+                    println!("\t[CERTM] client certificate saved");
+                }
                 let new_cert = Cert {
-                    host: cert.host,
                     name: Some(data.name),
                     firm_id: Some(data.firm_id),
                     firm_name: Some(data.firm_name),
                     auth: Some(Auth {id: data.id, token: data.token}),
-                    data_port: cert.data_port,
-                    file_port: cert.file_port,
-                    stream_port: cert.stream_port
+                    pending_token: None,
+                    ..cert
                 };
                 return Ok(new_cert);
             },
@@ -78,14 +234,9 @@ fn register(cert: Cert) -> Result<Cert, Error> {
 
 fn reset_cert(cert: &Cert) -> Result<(), Error> {
     let new_cert = Cert {
-        host: cert.host.clone(),
-        data_port: cert.data_port,
-        file_port: cert.file_port,
-        stream_port: cert.stream_port,
-        name: cert.name.clone(),
-        firm_name: cert.firm_name.clone(),
         firm_id: None,
-        auth: None
+        auth: None,
+        ..cert.clone()
     };
     mos::write_cert(&new_cert)
 }
\ No newline at end of file
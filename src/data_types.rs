@@ -1,7 +1,7 @@
 use bevy_ecs::{system::Resource};
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Resource)]
+#[derive(Serialize, Deserialize, Resource, Clone)]
 pub struct Cert {
 	pub host: String,
 	pub data_port: u16,
@@ -12,7 +12,302 @@ pub struct Cert {
     pub firm_id: Option<i32>,
     pub firm_name: Option<String>,
 
-	pub auth: Option<data_server::Auth>
+	pub auth: Option<data_server::Auth>,
+	#[serde(default)]
+	pub pending_token: Option<Vec<u8>>,		// rotated token saved before confirm, promoted to auth after
+
+	#[serde(default)]
+	pub shell: ShellPolicy,
+	#[serde(default)]
+	pub transfer: TransferPolicy,
+	#[serde(default)]
+	pub tunnel: TunnelPolicy,
+	#[serde(default)]
+	pub recording: RecordingPolicy,
+	#[serde(default)]
+	pub logs: LogPolicy,
+	#[serde(default)]
+	pub stats: StatPolicy,
+	#[serde(default)]
+	pub exporter: ExporterPolicy,
+	#[serde(default)]
+	pub compression: CompressionPolicy,
+	#[serde(default)]
+	pub tls: TlsPolicy,
+	#[serde(default)]
+	pub framing: FramingPolicy,
+	#[serde(default)]
+	pub signing: SigningPolicy,
+	#[serde(default)]
+	pub downloads: DownloadPolicy,
+	#[serde(default)]
+	pub failover: FailoverPolicy,
+	#[serde(default)]
+	pub proxy: ProxyPolicy
+}
+
+impl Cert {
+	pub fn server_addr(&self) -> ServerAddr {
+		ServerAddr {
+			host: self.host.clone(),
+			data_port: self.data_port,
+			file_port: self.file_port,
+			stream_port: self.stream_port
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerAddr {
+	pub host: String,
+	pub data_port: u16,
+	pub file_port: u16,
+	pub stream_port: u16
+}
+
+/// Fallback servers tried in turn after main one while poll fails, next one after switch_after failures in a row,
+/// retry delay doubles from poll period up to max_backoff (jittered).
+/// While on fallback main server probed every reprobe_period and switched back to once it answers.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FailoverPolicy {
+	pub hosts: Vec<ServerAddr>,
+	pub max_backoff: u64,		// s
+	pub switch_after: u32,
+	pub reprobe_period: u64		// s
+}
+
+impl Default for FailoverPolicy {
+	fn default() -> Self {
+		Self {
+			hosts: Vec::new(),
+			max_backoff: 300,
+			switch_after: 3,
+			reprobe_period: 600
+		}
+	}
+}
+
+/// Local policy for remote shell streams, server can't change it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShellPolicy {
+	pub enabled: bool,
+	pub shell: String,
+	pub args: Vec<String>,
+	pub max_sessions: usize,
+	pub idle_timeout: u64		// secs, 0 - without timeout
+}
+
+impl Default for ShellPolicy {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			shell: String::from("/bin/bash"),
+			args: vec![String::from("-l")],
+			max_sessions: 1,
+			idle_timeout: 1800
+		}
+	}
+}
+
+/// Local policy for file transfer streams, paths allowed only inside roots.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TransferPolicy {
+	pub enabled: bool,
+	pub allow_put: bool,
+	pub program_dirs: bool,		// allow programs dirs in bin_path
+	pub roots: Vec<String>
+}
+
+impl Default for TransferPolicy {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			allow_put: false,
+			program_dirs: true,
+			roots: vec![String::from("/var/log")]
+		}
+	}
+}
+
+/// Local policy for tunnel streams, only targets from list can be connected,
+/// target is "host:port" or "unix:/path/to.sock".
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct TunnelPolicy {
+	pub enabled: bool,
+	pub targets: Vec<String>
+}
+
+/// Local policy for recording of relayed stream sessions.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RecordingPolicy {
+	pub enabled: bool,
+	pub dir: String,
+	pub max_file_size: u64,		// bytes, recording truncated after it
+	pub max_total_size: u64,	// bytes, oldest recordings removed after it
+	pub retention_days: u64
+}
+
+impl Default for RecordingPolicy {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			dir: String::from("./recordings"),
+			max_file_size: 10 * 1024 * 1024,
+			max_total_size: 100 * 1024 * 1024,
+			retention_days: 30
+		}
+	}
+}
+
+/// Local rate limit of program logs, per program, server may set other one in program config.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogPolicy {
+	pub rate: f64,			// logs/s
+	pub burst: u32
+}
+
+impl Default for LogPolicy {
+	fn default() -> Self {
+		Self {
+			rate: 10.0,
+			burst: 50
+		}
+	}
+}
+
+/// Local policy for aggregation of numeric stats before sending.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatPolicy {
+	pub window: u64,			// secs, 0 - every stat sent as is
+	pub max_window: u64,		// secs, window doubled up to it while backlog above limit
+	pub backlog_limit: usize	// queued stats
+}
+
+impl Default for StatPolicy {
+	fn default() -> Self {
+		Self {
+			window: 0,
+			max_window: 3600,
+			backlog_limit: 100
+		}
+	}
+}
+
+/// Local metrics exporter, listen is loopback "host:port" or "unix:/path/to.sock".
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ExporterPolicy {
+	pub enabled: bool,
+	pub listen: String
+}
+
+impl Default for ExporterPolicy {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			listen: String::from("127.0.0.1:9464")
+		}
+	}
+}
+
+/// Local policy for zstd compression on the wire.
+/// Data port messages framed and compression offered to server, requests compressed
+/// only after server answered framed; relayed streams compressed as a whole.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompressionPolicy {
+	pub data: bool,
+	pub stream: bool,
+	pub threshold: usize		// bytes, smaller data port requests sent uncompressed
+}
+
+impl Default for CompressionPolicy {
+	fn default() -> Self {
+		Self {
+			data: false,
+			stream: false,
+			threshold: 1024
+		}
+	}
+}
+
+/// TLS for data, file and stream ports. Server certificate checked against ca_file
+/// (public roots if not set), or only compared with server_pin (sha256 of DER, hex) if set.
+/// Client certificate issued at registration used if mutual.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TlsPolicy {
+	pub enabled: bool,
+	pub ca_file: Option<String>,
+	pub server_pin: Option<String>,
+	pub server_name: Option<String>,	// name in server certificate, host if not set
+	pub mutual: bool,
+	pub client_cert: String,
+	pub client_key: String
+}
+
+impl Default for TlsPolicy {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			ca_file: None,
+			server_pin: None,
+			server_name: None,
+			mutual: false,
+			client_cert: String::from("./client.crt"),
+			client_key: String::from("./client.key")
+		}
+	}
+}
+
+/// Persistent framed connection for data port requests, plain connection per request
+/// used if disabled or server not supports it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FramingPolicy {
+	pub enabled: bool,
+	pub idle_timeout: u64		// s, kept connection reopened after it
+}
+
+impl Default for FramingPolicy {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			idle_timeout: 60
+		}
+	}
+}
+
+/// Requests signed with token (HMAC-SHA256) instead of carrying it, token sent only on registration.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SigningPolicy {
+	pub enabled: bool
+}
+
+/// Local policy for build and asset downloads, so fleet rollout not saturates site uplink.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DownloadPolicy {
+	pub max_concurrent: usize,
+	pub rate_limit: u64,			// bytes/s for all downloads, 0 - unlimited
+	pub windows: Vec<String>		// local time "HH:MM-HH:MM", may cross midnight; empty - any time
+}
+
+impl Default for DownloadPolicy {
+	fn default() -> Self {
+		Self {
+			max_concurrent: 1,
+			rate_limit: 0,
+			windows: Vec::new()
+		}
+	}
+}
+
+/// Proxy for data, file and stream connections: "http://[user:pass@]host:port" (CONNECT),
+/// "socks5://[user:pass@]host:port" (host resolved locally) or "socks5h://..." (resolved by proxy).
+/// Without url taken from ALL_PROXY / HTTPS_PROXY / HTTP_PROXY if use_env, unsupported ones skipped,
+/// hosts from no_proxy and NO_PROXY connected directly.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ProxyPolicy {
+	pub url: Option<String>,
+	pub use_env: bool,
+	pub no_proxy: Vec<String>
 }
 
 #[derive(PartialEq)]
@@ -46,6 +341,8 @@ pub mod data_server {
 	use bevy_ecs::{prelude::Component, system::Resource};
 	use serde::{Serialize, Deserialize};
 
+	use super::stream_api::TransferRequest;
+
 	const PSTATUS_OK: i16 = 0;
 	const PSTATUS_WARNING: i16 = 1;
 	const PSTATUS_ERROR: i16 = 2;
@@ -64,6 +361,11 @@ pub mod data_server {
 	const RTYPE_START_PROGRAM: i16 = 8;
 	const RTYPE_POINT_CONFIG_UPDATE: i16 = 9;
 	const RTYPE_INTERNAL_ERROR: i16 = 20;
+	const RTYPE_STREAM_OPEN: i16 = 30;
+	const RTYPE_STREAM_CLOSE: i16 = 31;
+	const RTYPE_STREAM_DENIED: i16 = 32;
+	const RTYPE_RECORDINGS_UPLOAD: i16 = 33;
+	const RTYPE_OFFLINE: i16 = 34;
 	
 	const CMD_SELFUPDATE: i16 = 2;
 	const CMD_FORCE_SELFUPDATE: i16 = 3;
@@ -76,6 +378,8 @@ pub mod data_server {
 	const CMD_SOFT_REBOOT: i16 = 25;
 	const CMD_HARD_REBOOT: i16 = 26;
 	const CMD_INDICATE: i16 = 40;
+	const CMD_SET_LOG_LEVEL: i16 = 45;
+	const CMD_UPLOAD_RECORDINGS: i16 = 50;
 
 
 	#[derive(Serialize, Deserialize)]
@@ -89,7 +393,11 @@ pub mod data_server {
 		AddReport(Auth, Report),
 		Register(String, Option<String>),			// point_name, firm_name
 		SetStatus(Auth, ProgramStatus),
-		SetRunStatus(Auth, ProgramRunStatus)
+		SetRunStatus(Auth, ProgramRunStatus),
+		AddBatch(Auth, Vec<BatchItem>),			// -> AddBatchAnsw
+		Signed(i32, Signature, Vec<u8>),		// point_id, signature, encoded Request with empty token
+		ConfirmToken(Auth),						// with new token, server drops old one -> OkAnsw
+		Hello(Auth, Capabilities)				// first contact -> Capabilities, old server closes connection
 	}
 	
 	#[derive(Serialize, Deserialize, Clone)]
@@ -97,6 +405,14 @@ pub mod data_server {
 		pub id: i32,
 		pub token: Vec<u8>
 	}
+
+	/// See utils::sign.
+	#[derive(Serialize, Deserialize, Clone)]
+	pub struct Signature {
+		pub ts: i64,			// unix millis
+		pub nonce: u64,
+		pub sig: Vec<u8>
+	}
 	
 	#[derive(Serialize, Deserialize)]
 	pub struct OkAnsw {
@@ -110,7 +426,12 @@ pub mod data_server {
 		ProgramDataChanged,
 		Cmd(i32, CmdType),		// cmd_id, cmd_data
 		Stream(i32, i32),		// stream_id, point_program_id
-		NotReg
+		NotReg,
+		Shell(i32),				// stream_id
+		Transfer(i32, TransferRequest),	// stream_id, transfer
+		Tunnel(i32, String),	// stream_id, target
+		RotateToken(Vec<u8>),	// salt, new token = HMAC(token, salt), server accepts both until confirmed
+		NewToken(Vec<u8>)		// token, only over TLS, server accepts both until confirmed
 	}
 	
 	// - - - - - - - UPDATE DATA - - - - - - - - - //
@@ -128,7 +449,8 @@ pub mod data_server {
 		Build(i32),		// program_id
 		Asset(i32, bool),		// program_id, is_exists
 		Config(i32),	// config_id
-		Nothing
+		Nothing,
+		BuildPatch(i32, Vec<u8>, Vec<u8>)	// program_id, installed build hash, new build hash; only to device with FEATURE_DELTA
 	}
 	
 	// - - - - - - - POINT CONFIG - - - - - - - - //
@@ -137,8 +459,10 @@ pub mod data_server {
 		pub autoupdate: bool,
 		pub config_autoupdate: bool,
 		pub asset_autoupdate: bool,
-		pub log_level: i16,
-		pub configs: Vec<(i32, String)>
+		pub log_level: i16,			// logs with lower level not sent to server
+		pub configs: Vec<(i32, String)>,
+		#[serde(default)]
+		pub log_rate: Option<(f64, u32)>	// logs/s, burst; None - local policy, missing in config of old server
 	}
 
 	impl ProgramCustom {
@@ -245,7 +569,11 @@ pub mod data_server {
 		pub name: String,
 		pub firm_id: i32,
 		pub firm_name: String,
-		pub token: Vec<u8>
+		pub token: Vec<u8>,
+		#[serde(default)]
+		pub client_cert: Option<Vec<u8>>,		// PEM, issued for mutual TLS
+		#[serde(default)]
+		pub client_key: Option<Vec<u8>>			// PEM
 	}
 	
 	#[derive(Serialize, Deserialize)]
@@ -320,7 +648,9 @@ pub mod data_server {
 		HardStopProgram(i32),
 		SoftReboot,
 		HardReboot,
-		Indicate
+		Indicate,
+		UploadRecordings,
+		SetLogLevel(i32, Option<i16>)		// program_id, threshold (None - back to configured)
 	}
 	
 	impl CmdType {
@@ -336,11 +666,14 @@ pub mod data_server {
 				Self::HardStopProgram(_) => CMD_HARD_STOP_PROGRAM,
 				Self::SoftReboot => CMD_SOFT_REBOOT,
 				Self::HardReboot => CMD_HARD_REBOOT,
-				Self::Indicate => CMD_INDICATE
+				Self::Indicate => CMD_INDICATE,
+				Self::UploadRecordings => CMD_UPLOAD_RECORDINGS,
+				Self::SetLogLevel(_, _) => CMD_SET_LOG_LEVEL
 			}
 		}
 	
-		pub fn from_code(code: i16, program_id: Option<i32>) -> Result<Self, std::io::Error> {
+		/// level is log threshold argument of CMD_SET_LOG_LEVEL, None - back to configured.
+		pub fn from_code(code: i16, program_id: Option<i32>, level: Option<i16>) -> Result<Self, std::io::Error> {
 			match code {
 				CMD_SELFUPDATE => Ok(Self::Selfupdate),
 				CMD_FORCE_SELFUPDATE => Ok(Self::ForceSelfupdate),
@@ -353,6 +686,8 @@ pub mod data_server {
 				CMD_SOFT_REBOOT => Ok(Self::SoftReboot),
 				CMD_HARD_REBOOT => Ok(Self::HardReboot),
 				CMD_INDICATE => Ok(Self::Indicate),
+				CMD_UPLOAD_RECORDINGS => Ok(Self::UploadRecordings),
+				CMD_SET_LOG_LEVEL => Ok(Self::SetLogLevel(Self::opt(program_id)?, level)),
 				cmd => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unknown cmd code: {}", cmd)))
 			}
 		}
@@ -375,7 +710,12 @@ pub mod data_server {
 		StopProgram,
 		StartProgram,
 		PointConfigUpdate,
-		InternalError
+		InternalError,
+		StreamOpen,
+		StreamClose,
+		StreamDenied,
+		RecordingsUpload,
+		Offline
 	}
 	
 	impl ReportType {
@@ -389,7 +729,12 @@ pub mod data_server {
 				ReportType::StopProgram => RTYPE_STOP_PROGRAM,
 				ReportType::StartProgram => RTYPE_START_PROGRAM,
 				ReportType::PointConfigUpdate => RTYPE_POINT_CONFIG_UPDATE,
-				ReportType::InternalError => RTYPE_INTERNAL_ERROR
+				ReportType::InternalError => RTYPE_INTERNAL_ERROR,
+				ReportType::StreamOpen => RTYPE_STREAM_OPEN,
+				ReportType::StreamClose => RTYPE_STREAM_CLOSE,
+				ReportType::StreamDenied => RTYPE_STREAM_DENIED,
+				ReportType::RecordingsUpload => RTYPE_RECORDINGS_UPLOAD,
+				ReportType::Offline => RTYPE_OFFLINE
 			}
 		}
 	}
@@ -424,6 +769,137 @@ pub mod data_server {
 		pub data: Vec<u8>
 	}
 	
+	/// Numeric stat values rolled up over window.
+	#[derive(Serialize, Deserialize, Clone)]
+	pub struct AggStat {
+		pub delay: i64,			// from window end
+		pub name: String,
+		pub program_id: Option<i32>,	// None - manager itself or sender not resolved
+		pub window: u64,		// millis
+		pub count: u64,
+		pub min: f64,
+		pub max: f64,
+		pub mean: f64,
+		pub last: f64
+	}
+	
+	#[derive(Serialize, Deserialize, Clone)]
+	pub enum SendDataType {
+		Report(Report),
+		Stat(Stat),
+		Log(Log),
+		AggStat(AggStat)
+	}
+
+	impl SendDataType {
+		pub fn is_necessary(&self) -> bool {
+			match self {
+				Self::Report(_) => true,
+				Self::Stat(_) => true,
+				Self::Log(_) => false,
+				Self::AggStat(_) => true
+			}
+		}
+
+		/// Send order, lower first: reports, stats, logs.
+		pub fn priority(&self) -> usize {
+			match self {
+				Self::Report(_) => 0,
+				Self::Stat(_) => 1,
+				Self::Log(_) => 2,
+				Self::AggStat(_) => 1
+			}
+		}
+
+		pub fn set_delay(&mut self, delay: i64) {
+			match self {
+				Self::Report(r) => r.delay = delay,
+				Self::Stat(s) => s.delay = delay,
+				Self::Log(l) => l.delay = delay,
+				Self::AggStat(a) => a.delay = delay
+			}
+		}
+	}
+
+	/// Item with id unique for point, same id on every retry so server can drop duplicates.
+	#[derive(Serialize, Deserialize, Clone)]
+	pub struct BatchItem {
+		pub id: u64,
+		pub item: SendDataType
+	}
+
+	/// Ids of accepted items, including ones already stored before (duplicates).
+	#[derive(Serialize, Deserialize)]
+	pub struct AddBatchAnsw(pub Vec<u64>);
+
+	// - - - - - - - CAPABILITIES - - - - - - - //
+	// Device -> Hello with its capabilities, server -> its own, before any other request.
+	// Server that not answers is legacy: none of the features below used with it.
+
+	/// Raised on every change of encoded types.
+	pub const PROTOCOL_REVISION: u16 = 1;
+
+	pub const FEATURE_BATCH: &str = "batch";					// AddBatch
+	pub const FEATURE_COMPRESS: &str = "compress";				// compressed data port messages and streams
+	pub const FEATURE_FRAMED: &str = "framed";					// persistent framed data connection
+	pub const FEATURE_SIGNED: &str = "signed";					// Signed requests, signature in file requests
+	pub const FEATURE_TOKEN_ROTATION: &str = "token_rotation";	// RotateToken, NewToken, ConfirmToken
+	pub const FEATURE_RESUME: &str = "resume";					// resumed downloads
+	pub const FEATURE_OFFLINE_REPORT: &str = "offline_report";	// ReportType::Offline
+	pub const FEATURE_SHELL: &str = "shell";					// PollAnsw::Shell, stream_api::ExtRequest::Denied
+	pub const FEATURE_TRANSFER: &str = "transfer";				// PollAnsw::Transfer
+	pub const FEATURE_TUNNEL: &str = "tunnel";					// PollAnsw::Tunnel, stream_api::ExtRequest::Denied
+	pub const FEATURE_RECORDING: &str = "recording";			// CMD_UPLOAD_RECORDINGS
+	pub const FEATURE_DELTA: &str = "delta";					// GetUpdateDataAnsw::BuildPatch
+
+	/// Features are strings, so unknown ones of newer peer decoded fine.
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct Capabilities {
+		pub version: String,		// manager or server version
+		pub revision: u16,			// PROTOCOL_REVISION of peer
+		pub features: Vec<String>
+	}
+
+	impl Capabilities {
+		/// Peer that not answered Hello.
+		pub fn legacy() -> Self {
+			Self {version: String::new(), revision: 0, features: Vec::new()}
+		}
+
+		pub fn has(&self, feature: &str) -> bool {
+			self.features.iter().any(|f| f == feature)
+		}
+	}
+
+	// - - - - - - - FRAMED DATA CONNECTION - - - - - - - //
+	// Device -> MAGIC, FramedHello frame, server -> FramedHelloAnsw frame,
+	// then request/answer frames with request id (see utils::framed).
+
+	#[derive(Serialize, Deserialize)]
+	pub struct FramedHello {
+		pub versions: Vec<u16>		// supported by device
+	}
+
+	#[derive(Serialize, Deserialize)]
+	pub enum FramedHelloAnsw {
+		Ok(u16),					// version chosen by server
+		Unsupported
+	}
+
+	/// Data of manager stat about send queue, counters since manager start.
+	#[derive(Serialize, Deserialize)]
+	pub struct SendQueueStat {
+		pub reports: usize,			// items in memory by class
+		pub stats: usize,
+		pub logs: usize,
+		pub journal: usize,			// necessary items on disk, including ones in memory
+		pub dropped_logs: u64,
+		pub dropped: u64,			// necessary items lost because journal not available
+		pub spilled: u64,			// necessary items kept on disk only because of overflow
+		#[serde(default)]
+		pub reduced: u64			// aggregated stats sent to server without batching as last value only
+	}
+
 	#[derive(Serialize, Deserialize, PartialEq)]
 	pub struct ProgramStatus {
 		pub name: String,
@@ -434,24 +910,35 @@ pub mod data_server {
 pub mod file_server {
 	use serde::{Serialize, Deserialize};
 
-	#[derive(Serialize, Deserialize)]
+	#[derive(Serialize, Deserialize, Clone)]
 	pub enum ResourceType {
 		Build,
-		Asset
+		Asset,
+		Recording(String, u64),		// file name, fsize - upload, data follows request, server answers with Answer
+		BuildPatch(Vec<u8>)			// base build hash - zstd patch-from base build archive to new one
 	}
 
-	#[derive(Serialize, Deserialize)]
+	#[derive(Serialize, Deserialize, Clone)]
 	pub struct Request {
 		pub point_id: i32,
 		pub point_program_id: i32,
-		pub token: Vec<u8>,
+		pub token: Vec<u8>,			// empty if signed
 		pub res_type: ResourceType
 	}
 
+	/// Sent instead of Request only when enabled by local policy (Signed) or supported by server (Resume).
+	#[derive(Serialize, Deserialize)]
+	pub enum ExtRequest {
+		Signed(i32, super::data_server::Signature, Vec<u8>),	// point_id, signature over body, encoded Request or Resume with empty token
+		Resume(Request, u64, Vec<u8>)		// offset, sha256 of file partial belongs to - resumed if server file the same
+	}
+
 	#[derive(Serialize, Deserialize)]
 	pub struct Answer {
 		pub hash: Vec<u8>,
-		pub fsize: u32
+		pub fsize: u32,
+		#[serde(default)]
+		pub offset: u64				// data follows from it, 0 if not resumed
 	}
 }
 
@@ -461,7 +948,62 @@ pub mod stream_api {
 
 	#[derive(Serialize, Deserialize)]
 	pub struct Request {
-		pub id: i32,
-		pub initiator: bool
+		pub id: i32,			// stream_id, point_program_id if initiator
+		pub initiator: bool		// stream opened by point (program asked for support)
+	}
+
+	/// Sent instead of Request only to server with negotiated feature.
+	#[derive(Serialize, Deserialize)]
+	pub enum ExtRequest {
+		Compressed(Request),		// after it both directions are zstd stream
+		Denied(Request, String)		// reason, stream opened by server refused, connection closed after it
+	}
+
+	// - - - - - - - FILE TRANSFER - - - - - - - //
+	// After Request all messages are frames: u32 BE length + data.
+	// Get: device -> Ready, data frames, empty frame.
+	// Put: device -> Ready, server -> data frames from offset, empty frame, device -> TransferResult.
+
+	#[derive(Serialize, Deserialize, Clone)]
+	pub enum TransferRequest {
+		Get(String, u64),				// path, offset - file from point to server
+		Put(String, u64, Vec<u8>)		// path, fsize, sha256 - file from server to point
+	}
+
+	#[derive(Serialize, Deserialize)]
+	pub enum TransferAnsw {
+		Ready(u64, u64, Vec<u8>),		// fsize, offset, sha256
+		Denied(String)
+	}
+
+	#[derive(Serialize, Deserialize)]
+	pub enum TransferResult {
+		Ok,
+		Fail(String)
+	}
+
+	// - - - - - - - RECORDING - - - - - - - //
+	// Recording file is sequence of frames: RecordHeader, then RecordFrame for every relayed chunk.
+
+	#[derive(Serialize, Deserialize)]
+	pub struct RecordHeader {
+		pub stream_id: i32,
+		pub program_id: Option<i32>,
+		pub kind: String,
+		pub started: i64			// unix time, ms
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Copy)]
+	pub enum RecordDirection {
+		In,							// server -> point
+		Out,						// point -> server
+		Truncated					// size limit reached, no more frames
+	}
+
+	#[derive(Serialize, Deserialize)]
+	pub struct RecordFrame {
+		pub time: u64,				// ms since started
+		pub dir: RecordDirection,
+		pub data: Vec<u8>
 	}
 }
\ No newline at end of file
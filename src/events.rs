@@ -2,6 +2,7 @@ use crate::stages;
 use crate::data_types;
 use bevy_ecs::prelude::*;
 use data_types::data_server::*;
+use data_types::stream_api::TransferRequest;
 
 pub struct PointUpdateAvailable;
 pub struct ProgramUpdateAvailable;
@@ -15,8 +16,32 @@ pub struct Stream {
 	pub program_id: i32
 }
 
+pub struct ShellStream {
+	pub id: i32
+}
+
+pub struct TransferStream {
+	pub id: i32,
+	pub req: TransferRequest
+}
+
+pub struct TunnelStream {
+	pub id: i32,
+	pub target: String
+}
+
+pub struct SupportRequest {
+	pub program_id: i32,
+	pub descr: Option<String>
+}
+
 pub struct NotReg;
 
+pub enum RotateToken {
+	Derive(Vec<u8>),	// salt
+	New(Vec<u8>)		// token
+}
+
 pub struct TerminateRequest {
 	pub pid: i32,
 	pub hard: bool
@@ -41,8 +66,23 @@ pub fn init(world: &mut World, schedule: &mut Schedule) {
 	world.init_resource::<Events<Stream>>();
 	schedule.add_system_to_stage(stages::Core::HandlePollEvents, Events::<Stream>::update_system);
 	
+	world.init_resource::<Events<ShellStream>>();
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, Events::<ShellStream>::update_system);
+	
+	world.init_resource::<Events<TransferStream>>();
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, Events::<TransferStream>::update_system);
+	
+	world.init_resource::<Events<TunnelStream>>();
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, Events::<TunnelStream>::update_system);
+	
+	world.init_resource::<Events<SupportRequest>>();
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, Events::<SupportRequest>::update_system);
+	
 	world.init_resource::<Events<NotReg>>();
 	schedule.add_system_to_stage(stages::Core::HandlePollEvents, Events::<NotReg>::update_system);
+
+	world.init_resource::<Events<RotateToken>>();
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, Events::<RotateToken>::update_system);
 	
 	world.init_resource::<Events<TerminateRequest>>();
 	schedule.add_system_to_stage(stages::Core::Main, Events::<TerminateRequest>::update_system);
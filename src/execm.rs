@@ -3,9 +3,10 @@
 
 use bevy_ecs::prelude::*;
 use std::io::Read;
-use std::process::Stdio;
+use std::process::{Stdio, ChildStdin};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, channel};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TrySendError, sync_channel};
 use std::thread;
 use std::time::{Instant, Duration};
 use std::{io::Error, process, process::Child};
@@ -17,6 +18,7 @@ use crate::data_types::data_server::ReportType;
 use crate::sendm::SendManager;
 use crate::utils::ipc::Ipc;
 use crate::utils::mos;
+use crate::utils::relay;
 use data_types::data_server::GetPointConfigAnsw as PointConfig;
 
 use crate::{stages, events};
@@ -25,6 +27,7 @@ const CLEAR_CNT_MAX: i32 = 5;
 pub const TERMINATE_CHECK_PERIOD: Duration = Duration::from_millis(5000);
 pub const TERMINATE_REQ_REPEAT_PERIOD: Duration = Duration::from_millis(5000);
 const STDOUT_BUFSIZE: usize = 4096;
+const STDOUT_QUEUE_CAP: usize = 64;
 
 
 #[derive(Component, Debug)]
@@ -36,13 +39,32 @@ pub struct Exec {
     pub entry: String,
     pub args_before: Option<String>,
     pub args_after: Option<String>,
-    pub is_custom: bool
+    pub is_custom: bool,
+    pub starts: u32
 }
 
 #[derive(Component)]
 pub struct Run {
     pub child: Child,
-    pub stdout: Option<Arc<Mutex<Receiver<Vec<u8>>>>>
+    pub stdout: Option<Arc<Mutex<Receiver<Vec<u8>>>>>,
+    pub stdin: Option<Arc<Mutex<ChildStdin>>>,
+    /// Stdout consumer attached: block program on full queue, otherwise drop output.
+    pub attached: Arc<AtomicBool>
+}
+
+impl Run {
+    pub fn attach(&self) {
+        self.attached.store(true, Ordering::Relaxed);
+    }
+
+    /// Release output consumer, drain queue so reader thread is not stuck on full queue.
+    pub fn detach(&self) {
+        self.attached.store(false, Ordering::Relaxed);
+        if let Some(stdout) = &self.stdout {
+            let rx = stdout.lock().unwrap();
+            while rx.try_recv().is_ok() {}
+        }
+    }
 }
 
 #[derive(Component)]
@@ -157,15 +179,18 @@ fn run_checker(mut cmd: Commands, mut execs: Query<(Entity, &Exec, &mut Run)>, m
     }
 }
 
+/// Execs neither running nor being terminated.
+type IdleFilter = (Without<Run>, Without<Terminate>);
+
 fn runner(
     mut cmd: Commands,
-    execs: Query<(Entity, &Exec), (Without<Run>, Without<Terminate>)>,
+    mut execs: Query<(Entity, &mut Exec), IdleFilter>,
     config: Res<ConfigBase>,
     mut evr: EventReader<events::RunRequest>,
     mut sm: ResMut<SendManager>
 ) {
     let mut run_ex = None;
-    'main_for: for (ex_e, ex) in &execs {
+    'main_for: for (ex_e, ex) in &mut execs {
         if ex.keep_run {
             run_ex = Some((ex_e, ex));
             break;
@@ -179,10 +204,11 @@ fn runner(
     }
 
     match run_ex {
-        Some((ex_e, ex)) => {
-            match run(ex, &config.bin_path) {
+        Some((ex_e, mut ex)) => {
+            match run(&ex, &config.bin_path) {
                 Some(r) => {
                     println!("[EXECM] start program {}", ex.name);
+                    ex.starts += 1;
                     cmd.entity(ex_e).insert(r);
                     sm.report(Report {delay: 0, rtype: ReportType::StartProgram, program_id: Some(ex.pid), descr: None});
                 },
@@ -209,7 +235,8 @@ fn startup(mut cmd: Commands, config: Res<PointConfig>) {
             entry: p.entry.clone(),
             args_after: p.args_after.clone(),
             args_before: p.args_before.clone(),
-            is_custom: p.ptype.is_custom()
+            is_custom: p.ptype.is_custom(),
+            starts: 0
         };
         println!("\t[EXECM] new: {:?}", ex);
         cmd.spawn(ex);
@@ -268,7 +295,17 @@ fn run(exec: &Exec, bin_path: &str) -> Option<Run> {
     match cmd.spawn() {
         Ok(mut child) => {
             let mut stdout = child.stdout.take();
-            let (tx, rx) = channel::<Vec<u8>>();
+            // relay must not block on program that not reads stdin
+            let stdin = child.stdin.take().and_then(|stdin| match relay::set_nonblocking(&stdin) {
+                Ok(_) => Some(Arc::new(Mutex::new(stdin))),
+                Err(e) => {
+                    println!("[EXECM] stdin of {} not attached: {:?}", exec.name, e);
+                    None
+                }
+            });
+            let (tx, rx) = sync_channel::<Vec<u8>>(STDOUT_QUEUE_CAP);
+            let attached = Arc::new(AtomicBool::new(false));
+            let attached_thr = attached.clone();
             thread::spawn(move || {
                 let mut buf: [u8;STDOUT_BUFSIZE] = [0;STDOUT_BUFSIZE];
                 loop {
@@ -279,9 +316,13 @@ fn run(exec: &Exec, bin_path: &str) -> Option<Run> {
                                     if len == 0 {
                                         break;
                                     }
-                                    match tx.send(buf[..len].to_vec()) {
-                                        Err(_) => break,
-                                        _ => ()
+                                    let data = buf[..len].to_vec();
+                                    if attached_thr.load(Ordering::Relaxed) {
+                                        if tx.send(data).is_err() {
+                                            break;
+                                        }
+                                    } else if let Err(TrySendError::Disconnected(_)) = tx.try_send(data) {
+                                        break;
                                     }
                                 },
                                 Err(_) => break
@@ -294,7 +335,9 @@ fn run(exec: &Exec, bin_path: &str) -> Option<Run> {
             });
             Some(Run {
                 child: child,
-                stdout: Some(Arc::new(Mutex::new(rx)))
+                stdout: Some(Arc::new(Mutex::new(rx))),
+                stdin,
+                attached
             })
         },
         Err(_) => None
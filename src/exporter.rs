@@ -0,0 +1,196 @@
+//! Exporter, optional local metrics endpoint for Prometheus.
+//! Snapshot of program states, send queue, server connectivity and numeric program stats
+//! rendered periodically in OpenMetrics text format and served over HTTP, thread per connection.
+
+use bevy_ecs::prelude::*;
+use std::fmt::Write as _;
+use std::io::{Error, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::data_types::Cert;
+use crate::execm::{Exec, Run};
+use crate::sendm::SendManager;
+use crate::srvm::Server;
+use crate::stages;
+use crate::utils::err;
+
+const RENDER_PERIOD: Duration = Duration::from_secs(1);
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_REQUEST_SIZE: usize = 8192;
+const UNIX_PREFIX: &str = "unix:";
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Resource)]
+pub struct Exporter {
+	snapshot: Arc<Mutex<String>>,
+	tl_render: Instant
+}
+
+fn escape(val: &str) -> String {
+	val.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// OpenMetrics number, non-finite values spelled as format requires.
+fn number(val: f64) -> String {
+	if val.is_nan() {
+		String::from("NaN")
+	} else if val.is_infinite() {
+		String::from(if val > 0.0 { "+Inf" } else { "-Inf" })
+	} else {
+		val.to_string()
+	}
+}
+
+fn family(out: &mut String, name: &str, mtype: &str, help: &str) {
+	let _ = writeln!(out, "# TYPE {} {}", name, mtype);
+	let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+fn render(execs: &Query<(&Exec, Option<&Run>)>, sm: &SendManager, server: &Server) -> String {
+	let mut out = String::new();
+
+	family(&mut out, "manager_server_connected", "gauge", "Last poll of server succeeded.");
+	let _ = writeln!(out, "manager_server_connected {}", server.is_connect() as u8);
+
+	family(&mut out, "manager_program_running", "gauge", "Program process is running.");
+	for (ex, run) in execs {
+		let _ = writeln!(out, "manager_program_running{{program=\"{}\"}} {}", escape(&ex.name), run.is_some() as u8);
+	}
+	family(&mut out, "manager_program_restarts", "counter", "Program restarts since manager start.");
+	for (ex, _) in execs {
+		let _ = writeln!(out, "manager_program_restarts_total{{program=\"{}\"}} {}", escape(&ex.name), ex.starts.saturating_sub(1));
+	}
+
+	let q = sm.queue_stat();
+	family(&mut out, "manager_send_queue_items", "gauge", "Items waiting for send in memory by class.");
+	let _ = writeln!(out, "manager_send_queue_items{{class=\"reports\"}} {}", q.reports);
+	let _ = writeln!(out, "manager_send_queue_items{{class=\"stats\"}} {}", q.stats);
+	let _ = writeln!(out, "manager_send_queue_items{{class=\"logs\"}} {}", q.logs);
+	family(&mut out, "manager_send_journal_items", "gauge", "Necessary items in send journal.");
+	let _ = writeln!(out, "manager_send_journal_items {}", q.journal);
+	family(&mut out, "manager_send_dropped_logs", "counter", "Logs dropped on queue overflow or send failure.");
+	let _ = writeln!(out, "manager_send_dropped_logs_total {}", q.dropped_logs);
+	family(&mut out, "manager_send_dropped", "counter", "Necessary items dropped because journal not available.");
+	let _ = writeln!(out, "manager_send_dropped_total {}", q.dropped);
+	family(&mut out, "manager_send_spilled", "counter", "Necessary items kept on disk only on queue overflow.");
+	let _ = writeln!(out, "manager_send_spilled_total {}", q.spilled);
+	family(&mut out, "manager_send_reduced", "counter", "Aggregated stats sent to server without batching as last value only.");
+	let _ = writeln!(out, "manager_send_reduced_total {}", q.reduced);
+
+	family(&mut out, "manager_program_stat", "gauge", "Last value of numeric stat pushed by program.");
+	let mut stats: Vec<(&(Option<i32>, String), &f64)> = sm.last_values().iter().collect();
+	stats.sort_by(|a, b| a.0.cmp(b.0));
+	for ((pid, name), val) in stats {
+		// empty program - manager itself or sender not resolved
+		let program = pid.and_then(|pid| execs.iter().find(|(ex, _)| ex.pid == pid)).map(|(ex, _)| ex.name.as_str()).unwrap_or("");
+		let _ = writeln!(out, "manager_program_stat{{program=\"{}\",name=\"{}\"}} {}", escape(program), escape(name), number(*val));
+	}
+
+	out.push_str("# EOF\n");
+	out
+}
+
+/// Read request head and answer with snapshot, request itself not inspected.
+/// Head must come within IO_TIMEOUT in total, so dripping client can't keep connection.
+fn serve<S: Read + Write>(mut stream: S, snapshot: &Mutex<String>) -> Result<(), Error> {
+	let started = Instant::now();
+	let mut req = Vec::new();
+	let mut buf = [0;1024];
+	while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+		if started.elapsed() > IO_TIMEOUT {
+			return Err(err("request head timeout"));
+		}
+		let len = stream.read(&mut buf)?;
+		if len == 0 {
+			break;
+		}
+		req.extend_from_slice(&buf[..len]);
+		if req.len() > MAX_REQUEST_SIZE {
+			return Err(err("request too long"));
+		}
+	}
+	let body = snapshot.lock().unwrap().clone();
+	let head = format!(
+		"HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+		CONTENT_TYPE,
+		body.len()
+	);
+	stream.write_all(head.as_bytes())?;
+	stream.write_all(body.as_bytes())?;
+	stream.flush()
+}
+
+fn listen(addr: &str, snapshot: Arc<Mutex<String>>) -> Result<(), Error> {
+	match addr.strip_prefix(UNIX_PREFIX) {
+		Some(path) => {
+			let _ = std::fs::remove_file(path);
+			let listener = UnixListener::bind(path)?;
+			thread::spawn(move || {
+				for stream in listener.incoming().flatten() {
+					let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+					let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+					let snapshot = snapshot.clone();
+					thread::spawn(move || serve(stream, &snapshot));
+				}
+			});
+		},
+		None => {
+			let sa: SocketAddr = addr.parse().map_err(|_| err("invalid listen address"))?;
+			if !sa.ip().is_loopback() {
+				return Err(err("listen address must be loopback"));
+			}
+			let listener = TcpListener::bind(sa)?;
+			thread::spawn(move || {
+				for stream in listener.incoming().flatten() {
+					let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+					let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+					let snapshot = snapshot.clone();
+					thread::spawn(move || serve(stream, &snapshot));
+				}
+			});
+		}
+	}
+	Ok(())
+}
+
+fn renderer(
+	exporter: Option<ResMut<Exporter>>,
+	execs: Query<(&Exec, Option<&Run>)>,
+	sm: Res<SendManager>,
+	server: Res<Server>
+) {
+	let mut exporter = match exporter {
+		Some(exporter) => exporter,
+		None => return
+	};
+	if exporter.tl_render.elapsed() < RENDER_PERIOD {
+		return;
+	}
+	exporter.tl_render = Instant::now();
+	*exporter.snapshot.lock().unwrap() = render(&execs, &sm, &server);
+}
+
+fn startup(mut cmd: Commands, cert: Res<Cert>) {
+	if !cert.exporter.enabled {
+		return;
+	}
+	println!("[EXPORTER] startup..");
+	let snapshot = Arc::new(Mutex::new(String::from("# EOF\n")));
+	match listen(&cert.exporter.listen, snapshot.clone()) {
+		Ok(()) => {
+			println!("[EXPORTER] listen on {}", cert.exporter.listen);
+			cmd.insert_resource(Exporter {snapshot, tl_render: Instant::now()});
+		},
+		Err(e) => println!("[EXPORTER] fail to listen on {}: {:?}", cert.exporter.listen, e)
+	}
+}
+
+pub fn init(_world: &mut World, schedule: &mut Schedule) -> Result<(), Error> {
+	schedule.add_system_to_stage(stages::Startup::InitExporter, startup);
+	schedule.add_system_to_stage(stages::Core::Save, renderer);
+	Ok(())
+}
@@ -1,20 +1,28 @@
-/// IPC manager create and handle IPC server, put IPC server Resource.
-/// Handle IPC connections, redirect to send manager.
+//! IPC manager create and handle IPC server, put IPC server Resource.
+//! Handle IPC connections, redirect to send manager, support requests to streamer.
+//! Program logs below program log level dropped, rest rate limited per program (program config or local policy),
+//! suppressed logs reported periodically as one summary log.
+//! Stat sender resolved to program by peer pid of connection (program process or its descendant).
 
 use bevy_ecs::prelude::*;
 use mio::{Poll, Token, Interest};
 use mio::net::{UnixListener, UnixStream};
+use std::collections::HashMap;
 use std::fs;
 use std::io::{Error, Read, ErrorKind, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::configm::ConfigBase;
-use crate::data_types::data_server::{self, IpcType};
+use crate::data_types::{Cert, LogPolicy};
+use crate::data_types::data_server::{self, IpcType, CmdType, ModuleStatus, ProgramType, StatusCode};
 use crate::sendm::SendManager;
-use crate::stages;
+use crate::execm::{Exec, Run};
+use crate::{stages, events};
 use crate::utils::ipc::{self, RequestFromProgram, ResAnsw};
 use crate::utils::{rmp_decode, json_decode};
+use crate::utils::ratelimit::RateLimiter;
 use data_server::GetPointConfigAnsw as PointConfig;
 
 const SERVER_MP: Token = Token(0);
@@ -22,6 +30,8 @@ const SERVER_JSON: Token = Token(1);
 const POLL_TIMEOUT: Duration = Duration::from_micros(2500);
 const EVENTS_CAP: usize = 16;
 const BUFSIZE: usize = 4096;
+const LOG_SUMMARY_PERIOD: Duration = Duration::from_secs(10);
+const MAX_PARENT_DEPTH: usize = 8;
 
 pub enum ClientState {
 	Read([u8;BUFSIZE]),
@@ -37,7 +47,8 @@ pub enum HandleResult {
 pub struct IpcClient {
 	pub stream: UnixStream,
 	pub state: ClientState,
-	pub ipc_type: IpcType
+	pub ipc_type: IpcType,
+	pub peer_pid: Option<u32>
 }
 
 #[derive(Resource)]
@@ -48,6 +59,80 @@ pub struct IpcServer {
 	pub events: mio::Events
 }
 
+struct LogBucket {
+	limiter: RateLimiter,
+	suppressed: u64,
+	max_level: i16			// highest level among suppressed
+}
+
+/// Per program log threshold overrides (set by server command) and rate limit state.
+#[derive(Resource)]
+pub struct LogFilter {
+	policy: LogPolicy,
+	levels: HashMap<i32, i16>,
+	buckets: HashMap<i32, LogBucket>,
+	tl_summary: Instant
+}
+
+impl LogFilter {
+	pub fn new(policy: LogPolicy) -> Self {
+		Self {
+			policy,
+			levels: HashMap::new(),
+			buckets: HashMap::new(),
+			tl_summary: Instant::now()
+		}
+	}
+
+	/// Log passes if its level not below threshold and program has tokens left.
+	pub fn pass(&mut self, config: &PointConfig, pid: i32, level: i16) -> bool {
+		let threshold = match self.levels.get(&pid) {
+			Some(level) => *level,
+			None => configured_level(config, pid)
+		};
+		if level < threshold {
+			return false;
+		}
+		let (rate, burst) = configured_rate(config, pid).unwrap_or((self.policy.rate, self.policy.burst));
+		let bucket = self.buckets.entry(pid).or_insert_with(|| LogBucket {
+			limiter: RateLimiter::with_burst(rate, burst as f64),
+			suppressed: 0,
+			max_level: level
+		});
+		bucket.limiter.set_limits(rate, burst as f64);
+		if bucket.limiter.try_take() {
+			return true;
+		}
+		if bucket.suppressed == 0 || level > bucket.max_level {
+			bucket.max_level = level;
+		}
+		bucket.suppressed += 1;
+		false
+	}
+}
+
+fn configured_level(config: &PointConfig, pid: i32) -> i16 {
+	for p in &config.programs {
+		if p.id == pid {
+			if let ProgramType::Custom(custom) = &p.ptype {
+				return custom.log_level;
+			}
+		}
+	}
+	0
+}
+
+fn configured_rate(config: &PointConfig, pid: i32) -> Option<(f64, u32)> {
+	for p in &config.programs {
+		if p.id == pid {
+			if let ProgramType::Custom(custom) = &p.ptype {
+				return custom.log_rate;
+			}
+		}
+	}
+	None
+}
+
 fn handle_client(client: &mut IpcClient) -> Result<HandleResult, Error> {
 	let res = match client.state {
 		ClientState::Read(ref mut buf) => {
@@ -69,13 +154,53 @@ fn handle_client(client: &mut IpcClient) -> Result<HandleResult, Error> {
 	Ok(res)
 }
 
-fn incoming_handler(mut cmd: Commands, mut sm: ResMut<SendManager>, mut clients: Query<(Entity, &mut IpcClient)>, config: Res<PointConfig>) {
+/// Pid of process on other end of connection (SO_PEERCRED).
+fn peer_pid(stream: &UnixStream) -> Option<u32> {
+	let mut cred = libc::ucred {pid: 0, uid: 0, gid: 0};
+	let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+	let res = unsafe {
+		libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+	};
+	if res == 0 && cred.pid > 0 { Some(cred.pid as u32) } else { None }
+}
+
+fn parent_pid(pid: u32) -> Option<u32> {
+	let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+	// comm in parentheses may contain spaces
+	stat.rsplit_once(')')?.1.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Program whose process is pid or its ancestor.
+fn program_of(pid: u32, runs: &Query<(&Exec, &Run)>) -> Option<i32> {
+	let mut pid = pid;
+	for _ in 0..MAX_PARENT_DEPTH {
+		if let Some((ex, _)) = runs.iter().find(|(_, run)| run.child.id() == pid) {
+			return Some(ex.pid);
+		}
+		pid = parent_pid(pid)?;
+		if pid <= 1 {
+			return None;
+		}
+	}
+	None
+}
+
+fn incoming_handler(
+	mut cmd: Commands,
+	mut sm: ResMut<SendManager>,
+	mut filter: ResMut<LogFilter>,
+	mut clients: Query<(Entity, &mut IpcClient)>,
+	config: Res<PointConfig>,
+	runs: Query<(&Exec, &Run)>,
+	mut evw_support: EventWriter<events::SupportRequest>
+) {
 	for (ent, mut client) in &mut clients {
 		match handle_client(&mut client) {
 			Ok(res) => match res {
 				HandleResult::Request(req) => match req {
 					RequestFromProgram::Log(log) => {
-						if let Some(pid) = config.find_program_by_name(&log.name) {
+						let pid = config.find_program_by_name(&log.name);
+						if let Some(pid) = pid.filter(|pid| filter.pass(&config, *pid, log.level)) {
 							sm.log(data_server::Log {
 								delay: 0,
 								level: log.level,
@@ -90,12 +215,28 @@ fn incoming_handler(mut cmd: Commands, mut sm: ResMut<SendManager>, mut clients:
 						client.state = ClientState::Write(answ_raw);
 					},
 					RequestFromProgram::Stat(stat) => {
-						sm.stat(stat);
+						let pid = client.peer_pid.and_then(|pid| program_of(pid, &runs));
+						sm.stat(stat, pid);
 						let answ_raw = match &client.ipc_type {
 							IpcType::Msgpack => rmp_serde::to_vec(&ResAnsw::Ok).unwrap(),
 							IpcType::Json => serde_json::to_vec(&ResAnsw::Ok).unwrap()
 						};
 						client.state = ClientState::Write(answ_raw);
+					},
+					RequestFromProgram::Support(support) => {
+						let answ = match config.find_program_by_name(&support.name) {
+							Some(pid) => {
+								println!("[IPCM] program {} asks for support", support.name);
+								evw_support.send(events::SupportRequest {program_id: pid, descr: support.descr});
+								ResAnsw::Ok
+							},
+							None => ResAnsw::Err
+						};
+						let answ_raw = match &client.ipc_type {
+							IpcType::Msgpack => rmp_serde::to_vec(&answ).unwrap(),
+							IpcType::Json => serde_json::to_vec(&answ).unwrap()
+						};
+						client.state = ClientState::Write(answ_raw);
 					}
 				},
 				HandleResult::Ok => {
@@ -110,6 +251,41 @@ fn incoming_handler(mut cmd: Commands, mut sm: ResMut<SendManager>, mut clients:
 	}
 }
 
+fn log_summary(mut filter: ResMut<LogFilter>, mut sm: ResMut<SendManager>) {
+	if filter.tl_summary.elapsed() < LOG_SUMMARY_PERIOD {
+		return;
+	}
+	filter.tl_summary = Instant::now();
+	for (pid, bucket) in filter.buckets.iter_mut() {
+		if bucket.suppressed == 0 {
+			continue;
+		}
+		sm.log(data_server::Log {
+			delay: 0,
+			level: bucket.max_level,
+			module: ModuleStatus {
+				lstype: StatusCode::Warning,
+				module: String::from("manager"),
+				descr: format!("{} logs suppressed", bucket.suppressed)
+			},
+			program_id: *pid
+		});
+		bucket.suppressed = 0;
+	}
+}
+
+fn log_level_handler(mut evr: EventReader<events::Cmd>, mut filter: ResMut<LogFilter>) {
+	for ev in evr.iter() {
+		if let CmdType::SetLogLevel(pid, level) = ev.ctype {
+			println!("[IPCM] log level for program {}: {:?}", pid, level);
+			match level {
+				Some(level) => filter.levels.insert(pid, level),
+				None => filter.levels.remove(&pid)
+			};
+		}
+	}
+}
+
 fn server(mut cmd: Commands, mut srv: ResMut<IpcServer>) {
 	let srv = &mut *srv;
 	srv.poll.poll(&mut srv.events, Some(POLL_TIMEOUT)).unwrap();
@@ -118,10 +294,12 @@ fn server(mut cmd: Commands, mut srv: ResMut<IpcServer>) {
 			SERVER_MP => {
 				match srv.srv_mp.accept() {
 					Ok((stream, _)) => {
+						let peer_pid = peer_pid(&stream);
 						cmd.spawn(IpcClient {
 							stream: stream,
 							state: ClientState::Read([0;BUFSIZE]),
-							ipc_type: IpcType::Msgpack
+							ipc_type: IpcType::Msgpack,
+							peer_pid
 						});
 					}
 					_ => ()
@@ -130,10 +308,12 @@ fn server(mut cmd: Commands, mut srv: ResMut<IpcServer>) {
 			SERVER_JSON => {
 				match srv.srv_json.accept() {
 					Ok((stream, _)) => {
+						let peer_pid = peer_pid(&stream);
 						cmd.spawn(IpcClient {
 							stream: stream,
 							state: ClientState::Read([0;BUFSIZE]),
-							ipc_type: IpcType::Json
+							ipc_type: IpcType::Json,
+							peer_pid
 						});
 					}
 					_ => ()
@@ -144,9 +324,10 @@ fn server(mut cmd: Commands, mut srv: ResMut<IpcServer>) {
 	}
 }
 
-fn startup(mut cmd: Commands, config: Res<ConfigBase>) {
+fn startup(mut cmd: Commands, config: Res<ConfigBase>, cert: Res<Cert>) {
 	let ipc = ipc::Ipc::new(&config.ipc_dir);
 	cmd.insert_resource(ipc);
+	cmd.insert_resource(LogFilter::new(cert.logs.clone()));
 
 	let sock_path_mp = format!("{}/manager_mp", config.ipc_dir);
 	let sock_path_json = format!("{}/manager_json", config.ipc_dir);
@@ -181,5 +362,7 @@ pub fn init(_world: &mut World, schedule: &mut Schedule) -> Result<(), Error> {
 	schedule.add_system_to_stage(stages::Startup::InitIpcManager, startup);
 	schedule.add_system_to_stage(stages::Core::PollServer, server);
 	schedule.add_system_to_stage(stages::Core::HandlePollEvents, incoming_handler);
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, log_level_handler);
+	schedule.add_system_to_stage(stages::Core::Main, log_summary);
 	Ok(())
 }
\ No newline at end of file
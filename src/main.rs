@@ -17,6 +17,7 @@ pub mod ipcm;
 pub mod execm;
 pub mod program_updater;
 pub mod streamer;
+pub mod exporter;
 
 use data_types::data_server::{Report, ReportType};
 
@@ -46,6 +47,7 @@ fn main() -> Result<(), Error> {
     schedule.add_stage(stages::Startup::InitExecManager, SystemStage::parallel().with_run_criteria(ShouldRun::once));
     schedule.add_stage(stages::Startup::InitProgramUpdater, SystemStage::parallel().with_run_criteria(ShouldRun::once));
     schedule.add_stage(stages::Startup::InitStreamer, SystemStage::parallel().with_run_criteria(ShouldRun::once));
+    schedule.add_stage(stages::Startup::InitExporter, SystemStage::parallel().with_run_criteria(ShouldRun::once));
     schedule.add_stage(stages::Core::PollServer, SystemStage::parallel());
     schedule.add_stage(stages::Core::HandlePollEvents, SystemStage::parallel());
     schedule.add_stage(stages::Core::Main, SystemStage::parallel());
@@ -60,6 +62,7 @@ fn main() -> Result<(), Error> {
     execm::init(&mut world, &mut schedule)?;
     program_updater::init(&mut world, &mut schedule)?;
     streamer::init(&mut world, &mut schedule)?;
+    exporter::init(&mut world, &mut schedule)?;
 
     loop {
         schedule.run(&mut world);
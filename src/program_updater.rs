@@ -1,13 +1,17 @@
-/// Program updater manager, handle corresponding PollEvent(ProgramUpdateAvailable), perform periodic check updates.
-/// Observe change program hashes and save its on disk.
+//! Program updater manager, handle corresponding PollEvent(ProgramUpdateAvailable), perform periodic check updates.
+//! Observe change program hashes and save its on disk.
+//! Build and asset downloads scheduled: limited concurrency, only in allowed time windows.
+//! Build patched if server offers patch from installed build, full build downloaded if patch fails.
 
 use bevy_ecs::prelude::*;
 use std::io::Error;
 use std::thread::{JoinHandle, self};
+use chrono::{Local, NaiveTime};
 
 use crate::execm::{Exec, self};
 use crate::sendm::SendManager;
 use crate::{events, stages};
+use crate::data_types::Cert;
 use crate::data_types::data_server::{GetPointConfigAnsw as PointConfig, GetUpdateDataAnsw, GetProgramConfigAnsw, Report, ReportType, ProgramType, ProgramHashes};
 use crate::srvm::Server;
 use crate::utils::{mos, some_str};
@@ -118,6 +122,47 @@ pub struct UpdateStateNew;
 #[derive(Component)]
 pub struct UpdateStateGetData(Option<GetDataResult>);
 
+impl UpdateStateGetData {
+	fn is_download(&self) -> bool {
+		matches!(self.0, Some(GetDataResult::Build(_)) | Some(GetDataResult::Asset(_)))
+	}
+}
+
+/// Download limits from local policy, windows parsed once on startup.
+#[derive(Resource)]
+pub struct DownloadScheduler {
+	max_concurrent: usize,
+	windows: Vec<(NaiveTime, NaiveTime)>,
+	waiting: bool			// download deferred, for log on change
+}
+
+impl DownloadScheduler {
+	fn new(cert: &Cert) -> Self {
+		let mut windows = Vec::new();
+		for w in &cert.downloads.windows {
+			let parsed = w.split_once('-').and_then(|(from, to)| {
+				Some((NaiveTime::parse_from_str(from.trim(), "%H:%M").ok()?, NaiveTime::parse_from_str(to.trim(), "%H:%M").ok()?))
+			});
+			match parsed {
+				Some(window) => windows.push(window),
+				None => println!("[PU] invalid download window {}, ignored", w)
+			}
+		}
+		Self {max_concurrent: cert.downloads.max_concurrent.max(1), windows, waiting: false}
+	}
+
+	/// Window crossing midnight if from is after to, whole day if they are equal.
+	fn in_window(&self, now: NaiveTime) -> bool {
+		self.windows.is_empty() || self.windows.iter().any(|(from, to)| if from == to {
+			true
+		} else if from < to {
+			*from <= now && now < *to
+		} else {
+			*from <= now || now < *to
+		})
+	}
+}
+
 #[derive(Component)]
 pub struct UpdateStateTerminate;
 
@@ -131,7 +176,14 @@ pub struct UpdateStateReport;
 pub enum UpdateType {
 	Build,
 	Asset(bool),
-	Config(i32)
+	Config(i32),
+	BuildPatch(Vec<u8>, Vec<u8>)	// installed build hash, new build hash
+}
+
+impl UpdateType {
+	fn is_build(&self) -> bool {
+		matches!(self, UpdateType::Build | UpdateType::BuildPatch(_, _))
+	}
 }
 
 #[derive(Component)]
@@ -146,7 +198,8 @@ impl PartialEq for ProgramUpdate {
 		if self.pid != other.pid {
 			return false;
 		}
-		if self.utype != other.utype {
+		// full build and patch are the same update
+		if self.utype != other.utype && !(self.utype.is_build() && other.utype.is_build()) {
 			return false;
 		}
 		true
@@ -216,6 +269,10 @@ fn sys_update_checker(
 						None => None
 					}
 				},
+				GetUpdateDataAnsw::BuildPatch(pid, base_hash, hash) => {
+					println!("\t[PU] build patch for program {} update available", pid);
+					Some(ProgramUpdate {pid, utype: UpdateType::BuildPatch(base_hash, hash), data: None})
+				},
 				GetUpdateDataAnsw::Nothing => {
 					println!("\t [PU] nothing to update");
 					None
@@ -239,14 +296,35 @@ fn sys_update_checker(
 	}
 }
 
-fn sys_new_handler(mut cmd: Commands, server: Res<Server>, mut query: Query<(Entity, &mut ProgramUpdate), With<UpdateStateNew>>) {
+fn sys_new_handler(
+	mut cmd: Commands,
+	server: Res<Server>,
+	mut sched: ResMut<DownloadScheduler>,
+	mut query: Query<(Entity, &mut ProgramUpdate), With<UpdateStateNew>>,
+	getting: Query<&UpdateStateGetData>
+) {
+	let mut running = getting.iter().filter(|g| g.is_download()).count();
+	let in_window = sched.in_window(Local::now().time());
+	let mut waiting = false;
 	for (e, mut d) in &mut query {
 		let api = server.api.clone();
 		let pid = d.pid;
+		let is_download = d.utype.is_build() || d.utype == UpdateType::Asset(true);
+		if is_download {
+			if !in_window || running >= sched.max_concurrent {
+				waiting = true;
+				continue;
+			}
+			running += 1;
+		}
 		match d.utype {
 			UpdateType::Build => {
 				cmd.entity(e).insert(UpdateStateGetData {0: Some(GetDataResult::Build(thread::spawn(move || api.download_program(pid))))});
 			},
+			UpdateType::BuildPatch(ref base_hash, ref hash) => {
+				let (base_hash, hash) = (base_hash.clone(), hash.clone());
+				cmd.entity(e).insert(UpdateStateGetData(Some(GetDataResult::Build(thread::spawn(move || api.download_program_patch(pid, base_hash, hash))))));
+			},
 			UpdateType::Asset(exists) => {
 				if exists {
 					cmd.entity(e).insert(UpdateStateGetData {0: Some(GetDataResult::Asset(thread::spawn(move || api.download_asset(pid))))});
@@ -261,6 +339,10 @@ fn sys_new_handler(mut cmd: Commands, server: Res<Server>, mut query: Query<(Ent
 		};
 		cmd.entity(e).remove::<UpdateStateNew>();
 	}
+	if waiting && !sched.waiting {
+		println!("[PU] downloads deferred, {}", if in_window {"concurrency limit reached"} else {"outside download window"});
+	}
+	sched.waiting = waiting;
 }
 
 fn sys_get_handler(mut cmd: Commands, mut query: Query<(Entity, &mut ProgramUpdate, &mut UpdateStateGetData)>) {
@@ -429,11 +511,12 @@ fn sys_hash_saver(mut hashes: ResMut<ProgramHashesRes>, evr: EventReader<events:
 	}
 }
 
-fn startup(mut cmd: Commands) {
+fn startup(mut cmd: Commands, cert: Res<Cert>) {
 	println!("[PROGRAM_UPDATER] startup..");
 	let hashes = mos::read_programs_hashes();
 	let hashes = ProgramHashesRes {0: hashes};
 	cmd.insert_resource(hashes);
+	cmd.insert_resource(DownloadScheduler::new(&cert));
 }
 
 pub fn init(_world: &mut World, schedule: &mut Schedule) -> Result<(), Error> {
@@ -445,4 +528,64 @@ pub fn init(_world: &mut World, schedule: &mut Schedule) -> Result<(), Error> {
 	schedule.add_system_to_stage(stages::Core::Main, sys_apply_handler);
 	schedule.add_system_to_stage(stages::Core::Save, sys_hash_saver);
 	Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn time(val: &str) -> NaiveTime {
+		NaiveTime::parse_from_str(val, "%H:%M").unwrap()
+	}
+
+	fn scheduler(windows: &[(&str, &str)]) -> DownloadScheduler {
+		DownloadScheduler {
+			max_concurrent: 1,
+			windows: windows.iter().map(|(from, to)| (time(from), time(to))).collect(),
+			waiting: false
+		}
+	}
+
+	#[test]
+	fn no_windows_always_open() {
+		assert!(scheduler(&[]).in_window(time("13:37")));
+	}
+
+	#[test]
+	fn window_within_day() {
+		let s = scheduler(&[("02:00", "05:00")]);
+		assert!(!s.in_window(time("01:59")));
+		assert!(s.in_window(time("02:00")));
+		assert!(s.in_window(time("04:59")));
+		assert!(!s.in_window(time("05:00")));
+	}
+
+	#[test]
+	fn window_crossing_midnight() {
+		let s = scheduler(&[("22:00", "03:00")]);
+		assert!(s.in_window(time("22:00")));
+		assert!(s.in_window(time("23:59")));
+		assert!(s.in_window(time("00:00")));
+		assert!(s.in_window(time("02:59")));
+		assert!(!s.in_window(time("03:00")));
+		assert!(!s.in_window(time("12:00")));
+		assert!(!s.in_window(time("21:59")));
+	}
+
+	#[test]
+	fn equal_bounds_whole_day() {
+		let s = scheduler(&[("04:00", "04:00")]);
+		assert!(s.in_window(time("04:00")));
+		assert!(s.in_window(time("03:59")));
+		assert!(s.in_window(time("00:00")));
+	}
+
+	#[test]
+	fn any_of_windows() {
+		let s = scheduler(&[("01:00", "02:00"), ("23:00", "00:30")]);
+		assert!(s.in_window(time("01:30")));
+		assert!(s.in_window(time("00:15")));
+		assert!(!s.in_window(time("00:45")));
+		assert!(!s.in_window(time("12:00")));
+	}
+}
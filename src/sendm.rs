@@ -1,153 +1,809 @@
-/// Send manager, handle SendData - send data to server, save SendData on disk if needed.
+//! Send manager, handle SendData - send data to server, save SendData on disk if needed.
+//! Data sent in batches, every item acknowledged separately, only failed items stay in queue.
+//! Every item has id unique for point (unix micros, increasing, persisted mark), kept in journal and sent on
+//! every retry, so server drops duplicates of items it stored but failed to acknowledge.
+//! Necessary items written to journal before queued and removed from it after acknowledge,
+//! so they survive crash or power loss and are sent again after restart.
+//! Queue split by priority class (reports, stats, logs), each class bounded: on overflow
+//! oldest logs dropped, necessary items kept in journal only and loaded back when queue drains.
+//! Numeric stats rolled up over window before queued, window grows while stat backlog is big
+//! and stats already queued rolled up into coarser ones.
+//! Nothing sent before server capabilities known, to server without batching items sent one by one
+//! and stats not rolled up, aggregated stats queued before sent as raw stat with last value and counted.
 
 use bevy_ecs::prelude::*;
 use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::{io::Error, time::{Instant, Duration}};
+use std::{io::Error, time::{Instant, Duration}, collections::{HashMap, HashSet, VecDeque}};
 
-use crate::{utils::{mos}, data_types::{data_server::{Report, Stat, Log}, AppState}, srvm::Server, stages};
+use crate::{utils::{mos, rmp_encode, rmp_decode, journal::Journal, siapi::IntApi}, srvm::Server, stages};
+use crate::data_types::{AppState, Cert, StatPolicy, data_server::{Report, Stat, Log, AggStat, BatchItem, SendQueueStat, FEATURE_BATCH}};
+pub use crate::data_types::data_server::SendDataType;
 
-pub const MAX_SEND_QUEUE: usize = 25;
+pub const REPORT_QUEUE_CAP: usize = 200;
+pub const STAT_QUEUE_CAP: usize = 200;
+pub const LOG_QUEUE_CAP: usize = 500;
 pub const DISK_CHECK_PERIOD: Duration = Duration::from_secs(10);
 pub const TRY_SEND_PERIOD: Duration = Duration::from_millis(2000);
+pub const BATCH_MAX_ITEMS: usize = 100;
+pub const BATCH_MAX_SIZE: usize = 65536;
+pub const JOURNAL_PATH: &str = "./send_journal";
+pub const COMPACT_MIN_ACKED: usize = 100;
+pub const QUEUE_STAT_PERIOD: Duration = Duration::from_secs(60);
+pub const QUEUE_STAT_NAME: &str = "manager.send_queue";
 
-#[derive(Deserialize, Serialize)]
-pub enum SendDataType {
-	Report(Report),
-	Stat(Stat),
-	Log(Log)
+const CLASSES: usize = 3;
+const QUEUE_CAP: [usize;CLASSES] = [REPORT_QUEUE_CAP, STAT_QUEUE_CAP, LOG_QUEUE_CAP];
+
+struct StatWindow {
+    tl_start: Instant,
+    count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    last: f64
+}
+
+/// Stat key: program id (None - manager itself or unknown sender) and name.
+type StatKey = (Option<i32>, String);
+
+/// Numeric stats by program and name rolled up over current window.
+struct StatAggregator {
+    policy: StatPolicy,
+    level: u32,                 // window = policy.window * 2^level
+    windows: HashMap<StatKey, StatWindow>,
+    tl_adapt: Instant
 }
 
-impl SendDataType {
-    pub fn is_necessary(&self) -> bool {
-        match self {
-            Self::Report(_) => true,
-            Self::Stat(_) => true,
-            Self::Log(_) => false,
+impl StatAggregator {
+    fn new(policy: StatPolicy) -> Self {
+        Self {policy, level: 0, windows: HashMap::new(), tl_adapt: Instant::now()}
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.policy.window > 0
+    }
+
+    fn window(&self) -> Duration {
+        let max = self.policy.max_window.max(self.policy.window);
+        Duration::from_secs(self.policy.window.saturating_mul(1 << self.level).min(max))
+    }
+
+    fn add(&mut self, key: StatKey, val: f64) {
+        let w = self.windows.entry(key).or_insert_with(|| StatWindow {
+            tl_start: Instant::now(),
+            count: 0,
+            min: val,
+            max: val,
+            sum: 0.0,
+            last: val
+        });
+        w.count += 1;
+        w.min = w.min.min(val);
+        w.max = w.max.max(val);
+        w.sum += val;
+        w.last = val;
+    }
+
+    /// Coarser window while backlog above limit, back to base when it drained, once per window.
+    /// Return true if backlog above limit, so queued stats should be rolled up too.
+    fn adapt(&mut self, backlog: usize) -> bool {
+        if self.tl_adapt.elapsed() < self.window() {
+            return false;
+        }
+        self.tl_adapt = Instant::now();
+        let max = self.policy.max_window.max(self.policy.window);
+        if backlog > self.policy.backlog_limit {
+            if self.window().as_secs() < max {
+                self.level += 1;
+                println!("[SENDM] stat backlog {}, window {}s", backlog, self.window().as_secs());
+            }
+            return true;
         }
+        if backlog < self.policy.backlog_limit / 2 && self.level > 0 {
+            self.level = 0;
+            println!("[SENDM] stat backlog {}, window {}s", backlog, self.window().as_secs());
+        }
+        false
+    }
+
+    /// Finished windows, or all of them if force.
+    fn take_expired(&mut self, force: bool) -> Vec<AggStat> {
+        let window = self.window();
+        let expired: Vec<StatKey> = self.windows.iter()
+            .filter(|(_, w)| force || w.tl_start.elapsed() >= window)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired.into_iter().filter_map(|key| {
+            let w = self.windows.remove(&key)?;
+            Some(AggStat {
+                delay: 0,
+                name: key.1,
+                program_id: key.0,
+                window: w.tl_start.elapsed().as_millis() as u64,
+                count: w.count,
+                min: w.min,
+                max: w.max,
+                mean: w.sum / w.count as f64,
+                last: w.last
+            })
+        }).collect()
     }
 }
 
 pub struct SendData {
+	id: u64,
 	dt: DateTime<Utc>,
 	dtype: SendDataType
 }
 
 #[derive(Resource)]
 pub struct SendManager {
-    pub queue: Vec<SendData>,
+    queues: [VecDeque<SendData>;CLASSES],     // by SendDataType::priority
+    spilled: [bool;CLASSES],                  // class has items in journal not loaded to queue
+    journal: Option<Journal>,
+    last_id: u64,
+    agg: StatAggregator,
+    last_values: HashMap<StatKey, f64>,
+    dropped_logs: u64,
+    dropped: u64,
+    spilled_cnt: u64,
+    raw_stats: bool,                          // server without batching, numeric stats not rolled up
+    reduced: u64,
     tl_disk_check: Instant,
-    tl_try_send: Instant
+    tl_try_send: Instant,
+    tl_stat: Instant
 }
 
 impl Default for SendManager {
     fn default() -> Self {
         Self {
-            queue: Vec::new(),
+            queues: Default::default(),
+            spilled: [false;CLASSES],
+            journal: None,
+            last_id: 0,
+            agg: StatAggregator::new(StatPolicy::default()),
+            last_values: HashMap::new(),
+            dropped_logs: 0,
+            dropped: 0,
+            spilled_cnt: 0,
+            raw_stats: false,
+            reduced: 0,
             tl_disk_check: Instant::now(),
-            tl_try_send: Instant::now()
+            tl_try_send: Instant::now(),
+            tl_stat: Instant::now()
         }
     }
 }
 
 impl SendManager {
     pub fn report(&mut self, val: Report) {
-        self.queue.push(SendData {
-            dt: Utc::now(),
-            dtype: SendDataType::Report(val)
-        })
+        self.push(SendDataType::Report(val))
     }
 
-    pub fn stat(&mut self, val: Stat) {
-        self.queue.push(SendData {
-            dt: Utc::now(),
-            dtype: SendDataType::Stat(val)
-        })
+    /// Numeric stat aggregated if enabled and server takes aggregated stats, other sent as is.
+    /// program_id only keeps stats of different programs apart, not sent with raw stat.
+    pub fn stat(&mut self, val: Stat, program_id: Option<i32>) {
+        if let Some(num) = numeric(&val.data) {
+            self.last_values.insert((program_id, val.name.clone()), num);
+            if self.agg.is_enabled() && !self.raw_stats {
+                self.agg.add((program_id, val.name), num);
+                return;
+            }
+        }
+        self.push(SendDataType::Stat(val))
     }
 
     pub fn log(&mut self, val: Log) {
-        self.queue.push(SendData {
-            dt: Utc::now(),
-            dtype: SendDataType::Log(val)
-        })
+        self.push(SendDataType::Log(val))
     }
-}
 
-fn elapsed(dt: &DateTime<Utc>) -> i64 {
-    Utc::now().timestamp_millis() - dt.timestamp_millis() 
-}
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty()) && !self.spilled.contains(&true)
+    }
 
-fn sys_send_manager(server: Res<Server>, mut sm: ResMut<SendManager>) {
-    if sm.tl_try_send.elapsed() < TRY_SEND_PERIOD {
-        return;
+    fn push(&mut self, dtype: SendDataType) {
+        let dt = Utc::now();
+        let class = dtype.priority();
+        let necessary = dtype.is_necessary();
+        let id = self.next_id();
+        let mut journaled = false;
+        if let Some(journal) = &mut self.journal {
+            if necessary {
+                match rmp_encode(&dtype).and_then(|raw| journal.add(id, dt.timestamp_millis(), raw)) {
+                    Ok(()) => journaled = true,
+                    Err(e) => println!("[SENDM] fail to write journal: {:?}", e)
+                }
+            }
+        }
+        let queue = &mut self.queues[class];
+        let full = queue.len() >= QUEUE_CAP[class];
+        if !full && !self.spilled[class] {
+            queue.push_back(SendData {id, dt, dtype});
+        } else if !necessary {
+            queue.pop_front();
+            queue.push_back(SendData {id, dt, dtype});
+            self.dropped_logs += 1;
+        } else if journaled {
+            // newer than everything in queue, loaded back in order by refill
+            self.spilled[class] = true;
+            self.spilled_cnt += 1;
+        } else if !full {
+            queue.push_back(SendData {id, dt, dtype});
+        } else {
+            println!("[SENDM] queue overflow, journal not available, item dropped");
+            self.dropped += 1;
+        }
     }
-    if sm.queue.len() > 0 {
-        let first = &mut sm.queue[0];
-        let res = match first.dtype {
-            SendDataType::Report(ref mut r) => {
-                r.delay = elapsed(&first.dt);
-                server.api.send_report(r.clone())
-            },
-            SendDataType::Stat(ref mut s) => {
-                s.delay = elapsed(&first.dt);
-                server.api.send_stat(s.clone())
-            },
-            SendDataType::Log(ref mut l) => {
-                l.delay = elapsed(&first.dt);
-                match server.api.send_log(l.clone()) {_ => ()}
-                Ok(())
+
+    /// Unique id, increasing even if clock goes back, also across restarts while journal available.
+    fn next_id(&mut self) -> u64 {
+        let now = Utc::now().timestamp_micros().max(0) as u64;
+        self.last_id = now.max(self.last_id + 1);
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.reserve_id(self.last_id) {
+                println!("[SENDM] fail to write id mark: {:?}", e);
+            }
+        }
+        self.last_id
+    }
+
+    fn ack(&mut self, d: &SendData) {
+        if !d.dtype.is_necessary() {
+            return;
+        }
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.ack(d.id) {
+                println!("[SENDM] fail to write journal: {:?}", e);
+            }
+        }
+    }
+
+    /// Load spilled items of class from journal while queue has room.
+    fn refill(&mut self, class: usize) {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => {
+                self.spilled[class] = false;
+                return;
             }
         };
+        let queue = &mut self.queues[class];
+        let loaded: HashSet<u64> = queue.iter().map(|d| d.id).collect();
+        let free = QUEUE_CAP[class].saturating_sub(queue.len());
+        let mut more = false;
+        for (id, dt, data) in journal.items() {
+            if loaded.contains(&id) {
+                continue;
+            }
+            let dtype = match rmp_decode::<SendDataType>(data) {
+                Ok(dtype) if dtype.priority() == class => dtype,
+                _ => continue
+            };
+            if queue.len() - loaded.len() >= free {
+                more = true;
+                break;
+            }
+            queue.push_back(SendData {id, dt: from_millis(dt), dtype});
+        }
+        self.spilled[class] = more;
+    }
+
+    /// Load not acknowledged items from journal, move data saved by old versions into journal.
+    fn restore(&mut self) -> Result<(), Error> {
+        let mut journal = Journal::open(JOURNAL_PATH)?;
+        self.last_id = journal.last_id();
+        while let Some((dt, data)) = mos::temp_send_data_pop()? {
+            if rmp_decode::<SendDataType>(&data).is_ok() {
+                let id = self.next_id();
+                journal.reserve_id(id)?;
+                journal.add(id, dt, data)?;
+            }
+        }
+        let invalid: Vec<u64> = journal.items()
+            .filter(|(_, _, data)| rmp_decode::<SendDataType>(data).is_err())
+            .map(|(id, _, _)| id)
+            .collect();
+        for id in invalid {
+            println!("[SENDM] skip invalid journal item {}", id);
+            journal.ack(id)?;
+        }
+        println!("[SENDM] restored {} items from journal", journal.len());
+        self.journal = Some(journal);
+        for class in 0..CLASSES {
+            self.refill(class);
+        }
+        Ok(())
+    }
 
+    /// Batch from queue heads, taken items by class kept aside till settled.
+    fn take_batch(&mut self, max_items: usize) -> (Vec<BatchItem>, Vec<Vec<SendData>>) {
+        let (batch, counts) = collect_batch(&mut self.queues, max_items);
+        let sent = counts.iter()
+            .enumerate()
+            .map(|(class, cnt)| self.queues[class].drain(..*cnt).collect())
+            .collect();
+        (batch, sent)
+    }
+
+    /// Acknowledged items removed from journal, failed ones back to the head of queue in the same order.
+    /// If whole send failed, logs dropped as they don't wait for connection.
+    fn settle(&mut self, sent: Vec<Vec<SendData>>, res: Result<Vec<u64>, Error>) {
         match res {
-            Ok(()) => {
-                sm.queue.remove(0);
+            Ok(acks) => {
+                let acks: HashSet<u64> = acks.into_iter().collect();
+                let mut any_failed = false;
+                for (class, items) in sent.into_iter().enumerate() {
+                    let mut failed = Vec::new();
+                    for d in items {
+                        if acks.contains(&d.id) {
+                            self.ack(&d);
+                        } else {
+                            failed.push(d);
+                        }
+                    }
+                    any_failed |= !failed.is_empty();
+                    for d in failed.into_iter().rev() {
+                        self.queues[class].push_front(d);
+                    }
+                }
+                if any_failed {
+                    self.tl_try_send = Instant::now();
+                }
             },
-            Err(_) => sm.tl_try_send = Instant::now()
+            Err(_) => {
+                for (class, items) in sent.into_iter().enumerate() {
+                    for d in items.into_iter().rev() {
+                        if d.dtype.is_necessary() {
+                            self.queues[class].push_front(d);
+                        } else {
+                            self.dropped_logs += 1;
+                        }
+                    }
+                }
+                self.tl_try_send = Instant::now();
+            }
         }
     }
-}
 
-fn sys_disk_manager(mut sm: ResMut<SendManager>, st: Res<AppState>) {
-    if st.is_terminate() {
-        match sm.queue.pop() {
-            Some(val) => {
-                if val.dtype.is_necessary() {
-                    mos::temp_send_data_push(val.dt.timestamp_millis(), &rmp_serde::to_vec(&val.dtype).unwrap()).unwrap();
+    /// Roll up queued numeric stats of same program and name into one aggregated stat each,
+    /// rolled up stat journaled before its parts acknowledged. Spilled stats left as they are.
+    fn downsample(&mut self) {
+        let mut groups: HashMap<StatKey, Vec<SendData>> = HashMap::new();
+        let mut keep = VecDeque::new();
+        for d in self.queues[1].drain(..) {
+            match stat_key(&d.dtype) {
+                Some(key) => groups.entry(key).or_default().push(d),
+                None => keep.push_back(d)
+            }
+        }
+        let before = groups.values().map(|g| g.len()).sum::<usize>() + keep.len();
+        let mut merged = Vec::new();
+        for (key, group) in groups {
+            if group.len() < 2 {
+                keep.extend(group);
+                continue;
+            }
+            let (dt, a) = roll_up(key, &group);
+            let id = self.next_id();
+            let dtype = SendDataType::AggStat(a);
+            if let Some(journal) = &mut self.journal {
+                if let Err(e) = rmp_encode(&dtype).and_then(|raw| journal.add(id, dt.timestamp_millis(), raw)) {
+                    println!("[SENDM] fail to write journal, stats not rolled up: {:?}", e);
+                    keep.extend(group);
+                    continue;
                 }
+            }
+            for d in &group {
+                self.ack(d);
+            }
+            merged.push(SendData {id, dt, dtype});
+        }
+        keep.extend(merged);
+        keep.make_contiguous().sort_by_key(|d| d.dt);
+        println!("[SENDM] queued stats rolled up from {} to {}", before, keep.len());
+        self.queues[1] = keep;
+    }
+
+    /// Stats waiting for send, spilled ones counted by whole journal.
+    fn stat_backlog(&self) -> usize {
+        let spilled = match (&self.journal, self.spilled[1]) {
+            (Some(journal), true) => journal.len(),
+            _ => 0
+        };
+        self.queues[1].len() + spilled
+    }
+
+    /// Last value of every numeric stat by program and name.
+    pub fn last_values(&self) -> &HashMap<StatKey, f64> {
+        &self.last_values
+    }
+
+    pub fn queue_stat(&self) -> SendQueueStat {
+        SendQueueStat {
+            reports: self.queues[0].len(),
+            stats: self.queues[1].len(),
+            logs: self.queues[2].len(),
+            journal: self.journal.as_ref().map(|j| j.len()).unwrap_or(0),
+            dropped_logs: self.dropped_logs,
+            dropped: self.dropped,
+            spilled: self.spilled_cnt,
+            reduced: self.reduced
+        }
+    }
+}
+
+/// Value of stat explicitly encoded as one msgpack int or float filling whole data,
+/// so text, json or binary stats that happen to start with fixint byte are not taken as numbers.
+/// One printable byte is taken as text, small ints in that range sent as is.
+fn numeric(data: &[u8]) -> Option<f64> {
+    match data {
+        [0x20..=0x7e] => return None,
+        [0x00..=0x7f | 0xe0..=0xff | 0xca..=0xd3, ..] => (),
+        _ => return None
+    }
+    let mut rest = data;
+    let val: f64 = rmp_serde::decode::from_read(&mut rest).ok()?;
+    if rest.is_empty() { Some(val) } else { None }
+}
+
+/// Key of queued stat that can be rolled up: aggregated or numeric raw one.
+fn stat_key(dtype: &SendDataType) -> Option<StatKey> {
+    match dtype {
+        SendDataType::AggStat(a) => Some((a.program_id, a.name.clone())),
+        SendDataType::Stat(s) => numeric(&s.data).map(|_| (None, s.name.clone())),
+        _ => None
+    }
+}
+
+/// One aggregated stat over windows of group, raw stat taken as window of one value ending when queued.
+/// Return it with time of its end.
+fn roll_up(key: StatKey, group: &[SendData]) -> (DateTime<Utc>, AggStat) {
+    let mut a = AggStat {delay: 0, name: key.1, program_id: key.0, window: 0, count: 0, min: f64::INFINITY, max: f64::NEG_INFINITY, mean: 0.0, last: 0.0};
+    let (mut start, mut end) = (i64::MAX, i64::MIN);
+    let mut sum = 0.0;
+    for d in group {
+        let (window, count, min, max, mean, last) = match &d.dtype {
+            SendDataType::AggStat(x) => (x.window, x.count, x.min, x.max, x.mean, x.last),
+            SendDataType::Stat(s) => {
+                let val = numeric(&s.data).unwrap_or(0.0);
+                (0, 1, val, val, val, val)
             },
-            None => ()
+            _ => continue
+        };
+        let dt = d.dt.timestamp_millis();
+        start = start.min(dt - window as i64);
+        if dt >= end {
+            end = dt;
+            a.last = last;
+        }
+        a.count += count;
+        a.min = a.min.min(min);
+        a.max = a.max.max(max);
+        sum += mean * count as f64;
+    }
+    a.window = (end - start).max(0) as u64;
+    a.mean = sum / a.count.max(1) as f64;
+    (from_millis(end), a)
+}
+
+fn from_millis(dt: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(dt).single().unwrap_or_else(Utc::now)
+}
+
+fn elapsed(dt: &DateTime<Utc>) -> i64 {
+    Utc::now().timestamp_millis() - dt.timestamp_millis() 
+}
+
+/// Take items from queue heads in priority order while batch limits allow, at least one item.
+/// Return batch and count of taken items by class.
+fn collect_batch(queues: &mut [VecDeque<SendData>;CLASSES], max_items: usize) -> (Vec<BatchItem>, [usize;CLASSES]) {
+    let mut batch = Vec::new();
+    let mut counts = [0;CLASSES];
+    let mut size = 0;
+    for (class, queue) in queues.iter_mut().enumerate() {
+        for d in queue.iter_mut() {
+            d.dtype.set_delay(elapsed(&d.dt));
+            let len = rmp_encode(&d.dtype).map(|raw| raw.len()).unwrap_or(0);
+            if !batch.is_empty() && (batch.len() >= max_items || size + len > BATCH_MAX_SIZE) {
+                return (batch, counts);
+            }
+            size += len;
+            batch.push(BatchItem {id: d.id, item: d.dtype.clone()});
+            counts[class] += 1;
+        }
+    }
+    (batch, counts)
+}
+
+/// Send item by its own request to server without batching, return its id if accepted.
+fn send_single(api: &IntApi, item: BatchItem) -> Result<Vec<u64>, Error> {
+    match item.item {
+        SendDataType::Report(report) => api.send_report(report)?,
+        SendDataType::Stat(stat) => api.send_stat(stat)?,
+        SendDataType::Log(log) => api.send_log(log)?,
+        // no request for it, last value of window sent as raw stat, counted by caller
+        SendDataType::AggStat(a) => {
+            println!("[SENDM] server without batching, stat {} of {} values sent as last value only", a.name, a.count);
+            api.send_stat(Stat {delay: a.delay, name: a.name, data: rmp_encode(&a.last)?})?
+        }
+    }
+    Ok(vec![item.id])
+}
+
+fn sys_send_manager(server: Res<Server>, mut sm: ResMut<SendManager>) {
+    if sm.tl_try_send.elapsed() < TRY_SEND_PERIOD {
+        return;
+    }
+    let drained: Vec<usize> = (0..CLASSES)
+        .filter(|&class| sm.spilled[class] && sm.queues[class].len() < QUEUE_CAP[class] / 2)
+        .collect();
+    for class in drained {
+        sm.refill(class);
+    }
+    let batching = match server.api.peer() {
+        Some(peer) => peer.has(FEATURE_BATCH),
+        None => return
+    };
+    if sm.raw_stats == batching {
+        sm.raw_stats = !batching;
+        println!("[SENDM] server {} batching, numeric stats {}", if batching {"has"} else {"without"}, if batching {"rolled up"} else {"sent as is"});
+    }
+    let (mut batch, sent) = sm.take_batch(if batching { BATCH_MAX_ITEMS } else { 1 });
+    if batch.is_empty() {
+        return;
+    }
+    let res = if batching {
+        server.api.send_batch(batch)
+    } else {
+        let item = batch.remove(0);
+        let reduced = matches!(item.item, SendDataType::AggStat(_));
+        let res = send_single(&server.api, item);
+        if reduced && res.is_ok() {
+            sm.reduced += 1;
         }
+        res
+    };
+    sm.settle(sent, res);
+}
+
+fn sys_stat_aggregator(mut sm: ResMut<SendManager>, st: Res<AppState>) {
+    let backlog = sm.stat_backlog();
+    if sm.agg.adapt(backlog) && sm.agg.is_enabled() && !sm.raw_stats {
+        sm.downsample();
+    }
+    // on terminate unfinished windows sent too, so they are in journal before exit
+    for a in sm.agg.take_expired(st.is_terminate()) {
+        sm.push(SendDataType::AggStat(a));
+    }
+}
+
+fn sys_queue_stat(mut sm: ResMut<SendManager>) {
+    if sm.tl_stat.elapsed() < QUEUE_STAT_PERIOD {
         return;
     }
+    sm.tl_stat = Instant::now();
+    match rmp_encode(&sm.queue_stat()) {
+        Ok(data) => sm.stat(Stat {delay: 0, name: String::from(QUEUE_STAT_NAME), data}, None),
+        Err(e) => println!("[SENDM] fail to encode queue stat: {:?}", e)
+    }
+}
+
+/// Compact journal when enough acks collected or nothing left to send.
+fn sys_disk_manager(mut sm: ResMut<SendManager>) {
     if sm.tl_disk_check.elapsed() < DISK_CHECK_PERIOD {
         return;
     }
     sm.tl_disk_check = Instant::now();
-    if sm.queue.len() == 0 {
-        match mos::temp_send_data_pop().unwrap() {
-            Some((dt, data)) => {
-                sm.queue.push(SendData {
-                    dt: Utc.timestamp_millis_opt(dt).unwrap(),
-                    dtype: match rmp_serde::from_slice(&data) {
-                        Ok(data) => data,
-                        _ => return
-                    }
-                })
-            },
-            None => ()
+    let queue_empty = sm.is_empty();
+    if let Some(journal) = &mut sm.journal {
+        let acked = journal.acked();
+        if acked >= COMPACT_MIN_ACKED || (acked > 0 && queue_empty) {
+            if let Err(e) = journal.compact() {
+                println!("[SENDM] fail to compact journal: {:?}", e);
+            }
         }
     }
 }
 
-fn startup(mut cmd: Commands) {
+fn startup(mut cmd: Commands, cert: Res<Cert>) {
     println!("[SENDM] startup..");
-    cmd.insert_resource(SendManager::default());
+    let mut sm = SendManager {
+        agg: StatAggregator::new(cert.stats.clone()),
+        ..Default::default()
+    };
+    if let Err(e) = sm.restore() {
+        println!("[SENDM] journal not available, data not persisted: {:?}", e);
+    }
+    cmd.insert_resource(sm);
 }
 
 pub fn init(_world: &mut World, schedule: &mut Schedule) -> Result<(), Error> {
     schedule.add_system_to_stage(stages::Startup::InitSendManager, startup);
     schedule.add_system_to_stage(stages::Core::HandlePollEvents, sys_send_manager);
+    schedule.add_system_to_stage(stages::Core::Main, sys_queue_stat);
+    schedule.add_system_to_stage(stages::Core::Main, sys_stat_aggregator);
     schedule.add_system_to_stage(stages::Core::Save, sys_disk_manager);
 	Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::data_server::{ReportType, ModuleStatus, StatusCode};
+    use crate::utils::{err, test_dir};
+
+    fn report() -> SendDataType {
+        SendDataType::Report(Report {delay: 0, rtype: ReportType::Reboot, program_id: None, descr: None})
+    }
+
+    fn stat(name: &str, data: Vec<u8>) -> SendDataType {
+        SendDataType::Stat(Stat {delay: 0, name: String::from(name), data})
+    }
+
+    fn log() -> SendDataType {
+        let module = ModuleStatus {lstype: StatusCode::Ok, module: String::from("test"), descr: String::new()};
+        SendDataType::Log(Log {program_id: 1, delay: 0, level: 0, module})
+    }
+
+    fn ids(queue: &VecDeque<SendData>) -> Vec<u64> {
+        queue.iter().map(|d| d.id).collect()
+    }
+
+    fn with_journal(name: &str) -> SendManager {
+        let path = test_dir(name).join("journal");
+        SendManager {journal: Some(Journal::open(&path.to_string_lossy()).unwrap()), ..Default::default()}
+    }
+
+    #[test]
+    fn batch_in_priority_order_up_to_limit() {
+        let mut sm = SendManager::default();
+        sm.push(log());
+        sm.push(stat("a", rmp_encode(&1).unwrap()));
+        sm.push(report());
+        sm.push(stat("b", rmp_encode(&2).unwrap()));
+        let expected: Vec<u64> = vec![sm.queues[0][0].id, sm.queues[1][0].id, sm.queues[1][1].id];
+        let (batch, sent) = sm.take_batch(3);
+        assert_eq!(batch.iter().map(|b| b.id).collect::<Vec<u64>>(), expected);
+        assert_eq!(sent.iter().map(|s| s.len()).collect::<Vec<usize>>(), vec![1, 2, 0]);
+        assert_eq!(sm.queues[2].len(), 1);
+    }
+
+    #[test]
+    fn batch_has_at_least_one_item() {
+        let mut sm = SendManager::default();
+        sm.push(stat("big", vec![0;BATCH_MAX_SIZE + 1]));
+        sm.push(stat("small", vec![0]));
+        let (batch, _) = sm.take_batch(BATCH_MAX_ITEMS);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(sm.queues[1].len(), 1);
+    }
+
+    #[test]
+    fn not_acked_items_back_to_head_in_order() {
+        let mut sm = SendManager::default();
+        for _ in 0..4 {
+            sm.push(report());
+        }
+        let all = ids(&sm.queues[0]);
+        let (_, sent) = sm.take_batch(3);
+        sm.settle(sent, Ok(vec![all[1]]));
+        assert_eq!(ids(&sm.queues[0]), vec![all[0], all[2], all[3]]);
+    }
+
+    #[test]
+    fn acked_items_removed_from_journal() {
+        let mut sm = with_journal("sendm_ack");
+        sm.push(report());
+        sm.push(report());
+        let all = ids(&sm.queues[0]);
+        let (_, sent) = sm.take_batch(2);
+        sm.settle(sent, Ok(vec![all[0]]));
+        let journal: Vec<u64> = sm.journal.as_ref().unwrap().items().map(|(id, _, _)| id).collect();
+        assert_eq!(journal, vec![all[1]]);
+    }
+
+    #[test]
+    fn failed_send_keeps_necessary_drops_logs() {
+        let mut sm = SendManager::default();
+        sm.push(report());
+        sm.push(log());
+        let (_, sent) = sm.take_batch(2);
+        sm.settle(sent, Err(err("offline")));
+        assert_eq!(sm.queues[0].len(), 1);
+        assert!(sm.queues[2].is_empty());
+        assert_eq!(sm.dropped_logs, 1);
+    }
+
+    #[test]
+    fn log_overflow_drops_oldest() {
+        let mut sm = SendManager::default();
+        for _ in 0..LOG_QUEUE_CAP + 1 {
+            sm.push(log());
+        }
+        let first = sm.queues[2][0].id;
+        sm.push(log());
+        assert_eq!(sm.queues[2].len(), LOG_QUEUE_CAP);
+        assert!(sm.queues[2][0].id > first);
+        assert_eq!(sm.dropped_logs, 2);
+    }
+
+    #[test]
+    fn necessary_overflow_without_journal_dropped() {
+        let mut sm = SendManager::default();
+        for _ in 0..REPORT_QUEUE_CAP + 1 {
+            sm.push(report());
+        }
+        assert_eq!(sm.queues[0].len(), REPORT_QUEUE_CAP);
+        assert_eq!(sm.dropped, 1);
+    }
+
+    #[test]
+    fn necessary_overflow_spilled_and_refilled_in_order() {
+        let mut sm = with_journal("sendm_spill");
+        for _ in 0..REPORT_QUEUE_CAP + 5 {
+            sm.push(report());
+        }
+        assert_eq!(sm.queues[0].len(), REPORT_QUEUE_CAP);
+        assert!(sm.spilled[0]);
+        assert_eq!(sm.spilled_cnt, 5);
+        let mut last = 0;
+        let mut sent_cnt = 0;
+        while !sm.is_empty() {
+            let (batch, sent) = sm.take_batch(BATCH_MAX_ITEMS);
+            for b in &batch {
+                assert!(b.id > last);
+                last = b.id;
+            }
+            sent_cnt += batch.len();
+            sm.settle(sent, Ok(batch.iter().map(|b| b.id).collect()));
+            sm.refill(0);
+        }
+        assert_eq!(sent_cnt, REPORT_QUEUE_CAP + 5);
+    }
+
+    #[test]
+    fn numeric_only_whole_msgpack_number() {
+        assert_eq!(numeric(&rmp_encode(&5u8).unwrap()), Some(5.0));
+        assert_eq!(numeric(&rmp_encode(&-3i32).unwrap()), Some(-3.0));
+        assert_eq!(numeric(&rmp_encode(&1000u32).unwrap()), Some(1000.0));
+        assert_eq!(numeric(&rmp_encode(&1.5f64).unwrap()), Some(1.5));
+        assert_eq!(numeric(b"5"), None);
+        assert_eq!(numeric(b"42"), None);
+        assert_eq!(numeric(b"{\"a\":1}"), None);
+        assert_eq!(numeric(&rmp_encode(&"text").unwrap()), None);
+        assert_eq!(numeric(&[]), None);
+    }
+
+    #[test]
+    fn queued_stats_rolled_up_by_name() {
+        let mut sm = SendManager::default();
+        for val in [1.0, 5.0, 3.0] {
+            sm.push(stat("a", rmp_encode(&val).unwrap()));
+        }
+        sm.push(stat("b", b"text".to_vec()));
+        sm.push(stat("c", rmp_encode(&7).unwrap()));
+        sm.downsample();
+        assert_eq!(sm.queues[1].len(), 3);
+        let a = sm.queues[1].iter().find_map(|d| match &d.dtype {
+            SendDataType::AggStat(a) if a.name == "a" => Some(a),
+            _ => None
+        }).unwrap();
+        assert_eq!((a.count, a.min, a.max, a.mean, a.last), (3, 1.0, 5.0, 3.0, 3.0));
+        assert!(sm.queues[1].iter().any(|d| matches!(&d.dtype, SendDataType::Stat(s) if s.name == "b")));
+        assert!(sm.queues[1].iter().any(|d| matches!(&d.dtype, SendDataType::Stat(s) if s.name == "c")));
+    }
+
+    #[test]
+    fn roll_up_merges_windows() {
+        let dt = Utc::now();
+        let agg = |window: u64, count: u64, min: f64, max: f64, mean: f64, last: f64| SendDataType::AggStat(AggStat {
+            delay: 0, name: String::from("a"), program_id: Some(1), window, count, min, max, mean, last
+        });
+        let group = vec![
+            SendData {id: 1, dt: dt - chrono::Duration::seconds(60), dtype: agg(60_000, 2, 1.0, 3.0, 2.0, 3.0)},
+            SendData {id: 2, dt, dtype: agg(60_000, 2, 0.0, 8.0, 4.0, 0.0)}
+        ];
+        let (end, a) = roll_up((Some(1), String::from("a")), &group);
+        assert_eq!(end.timestamp_millis(), dt.timestamp_millis());
+        assert_eq!((a.window, a.count, a.min, a.max, a.mean, a.last), (120_000, 4, 0.0, 8.0, 3.0, 0.0));
+    }
+}
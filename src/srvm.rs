@@ -1,34 +1,104 @@
-/// Server manger, establish and maintain connect with manager server.
-/// Put IntApi Resource, perform periodic poll, generate PollEvent.
+//! Server manger, establish and maintain connect with manager server.
+//! Put IntApi Resource, perform periodic poll, generate PollEvent.
+//! While poll fails server is offline: retry delay grows (jittered), fallback servers tried in turn
+//! after several failures in a row, main server probed periodically while on fallback, outage reported after reconnect.
+//! Capabilities exchanged before first poll to every server and again after it was unreachable.
 
 use bevy_ecs::prelude::*;
-use std::{io::Error, time::Instant};
+use chrono::{DateTime, Local};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{io::Error, time::{Duration, Instant}};
 
-use crate::{data_types::{Cert, data_server::PollAnsw}, utils::siapi::{self, IntApi}, stages, events, configm::ConfigBase};
+use crate::{data_types::{Cert, data_server::{PollAnsw, Report, ReportType, FEATURE_OFFLINE_REPORT}}, utils::{siapi::{self, IntApi}, tls::TlsConnector, proxy::Proxy}, stages, events, configm::ConfigBase};
+use crate::sendm::SendManager;
+
+pub enum ConnState {
+	Online,
+	Offline {
+		since: DateTime<Local>,
+		failures: u32,
+		retry_at: Instant
+	}
+}
 
 #[derive(Resource)]
 pub struct Server {
 	pub api: IntApi,
-	pub is_connect: bool,
-	pub tl_poll: Instant
+	pub state: ConnState,
+	pub tl_poll: Instant,
+	pub tl_reprobe: Instant,	// last switch to fallback or probe of main server
+	pub hello_failed: bool		// hello not answered in time, asked again on next poll
 }
 
+impl Server {
+	pub fn is_connect(&self) -> bool {
+		matches!(self.state, ConnState::Online)
+	}
+}
+
+/// Poll period doubled on every failure up to max, random part cut so devices not retry in sync.
+fn backoff(period: Duration, failures: u32, max: Duration) -> Duration {
+	let delay = period.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(max).max(period);
+	let mut rnd = [0;1];
+	let _ = SystemRandom::new().fill(&mut rnd);
+	delay.mul_f64(0.5 + rnd[0] as f64 / 510.0)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn sys_poll(
     mut srv: ResMut<Server>,
     config: Res<ConfigBase>,
+    cert: Res<Cert>,
     mut evw_not_reg: EventWriter<events::NotReg>,
     mut evw_cmd: EventWriter<events::Cmd>,
     mut evw_pcua: EventWriter<events::PointUpdateAvailable>,
     mut evw_pua: EventWriter<events::ProgramUpdateAvailable>,
-    mut evw_stream: EventWriter<events::Stream>
+    mut evw_stream: EventWriter<events::Stream>,
+    mut evw_shell: EventWriter<events::ShellStream>,
+    mut evw_transfer: EventWriter<events::TransferStream>,
+    mut evw_tunnel: EventWriter<events::TunnelStream>,
+    mut evw_rotate: EventWriter<events::RotateToken>,
+    mut sm: ResMut<SendManager>
 ) {
-    if srv.tl_poll.elapsed() < config.poll_period {
+    // registration in progress; capabilities asked without waiting for poll period, sendm waits for them
+    if cert.auth.is_none() || (srv.tl_poll.elapsed() < config.poll_period && (srv.api.peer().is_some() || srv.hello_failed)) {
         return;
     }
+    if let ConnState::Offline {retry_at, ..} = srv.state {
+        if Instant::now() < retry_at {
+            return;
+        }
+    }
     srv.tl_poll = Instant::now();
-    match srv.api.poll() {
+    if srv.api.on_fallback() && srv.tl_reprobe.elapsed() >= Duration::from_secs(cert.failover.reprobe_period) {
+        srv.tl_reprobe = Instant::now();
+        srv.api.reprobe_main();
+    }
+    // capabilities exchanged on first contact with every server, hello not answered in time asked again on next poll
+    let res = match srv.api.peer() {
+        Some(_) => srv.api.poll(),
+        None => match srv.api.hello() {
+            Ok(_) => {
+                srv.hello_failed = false;
+                srv.api.poll()
+            },
+            Err(e) => {
+                println!("[SRVM] hello fail: {:?}, poll anyway", e);
+                srv.hello_failed = true;
+                srv.api.poll()
+            }
+        }
+    };
+    match res {
         Ok(answ) => {
-            srv.is_connect = true;
+            if let ConnState::Offline {since, ..} = srv.state {
+                let descr = format!("offline from {} to {}", since.format("%Y-%m-%d %H:%M:%S"), Local::now().format("%Y-%m-%d %H:%M:%S"));
+                println!("[SRVM] server reachable, {}", descr);
+                if srv.api.peer_has(FEATURE_OFFLINE_REPORT) {
+                    sm.report(Report {delay: 0, rtype: ReportType::Offline, program_id: None, descr: Some(descr)});
+                }
+            }
+            srv.state = ConnState::Online;
             match answ {
                 PollAnsw::Nothing => (),
                 PollAnsw::NotReg => evw_not_reg.send(events::NotReg),
@@ -36,23 +106,67 @@ fn sys_poll(
                 PollAnsw::PointConfigChanged => evw_pcua.send(events::PointUpdateAvailable),
                 PollAnsw::ProgramDataChanged => evw_pua.send(events::ProgramUpdateAvailable),
                 PollAnsw::Stream(id, program_id) => evw_stream.send(events::Stream {id: id, program_id: program_id}),
+                PollAnsw::Shell(id) => evw_shell.send(events::ShellStream {id}),
+                PollAnsw::Transfer(id, req) => evw_transfer.send(events::TransferStream {id, req}),
+                PollAnsw::Tunnel(id, target) => evw_tunnel.send(events::TunnelStream {id, target}),
+                PollAnsw::RotateToken(salt) => evw_rotate.send(events::RotateToken::Derive(salt)),
+                PollAnsw::NewToken(token) => evw_rotate.send(events::RotateToken::New(token)),
             }
         },
-        Err(_) => srv.is_connect = false
+        Err(e) => {
+            let (since, failures) = match srv.state {
+                ConnState::Online => (Local::now(), 1),
+                ConnState::Offline {since, failures, ..} => (since, failures + 1)
+            };
+            let delay = backoff(config.poll_period, failures, Duration::from_secs(cert.failover.max_backoff));
+            println!("[SRVM] poll fail ({} in a row): {:?}, retry in {:?}", failures, e, delay);
+            if failures % cert.failover.switch_after.max(1) == 0 && srv.api.failover() {
+                srv.tl_reprobe = Instant::now();
+            } else {
+                srv.api.reset_peer();
+            }
+            srv.state = ConnState::Offline {since, failures, retry_at: Instant::now() + delay};
+        }
     }
 }
 
 fn startup(mut cmd: Commands, cert: Res<Cert>) {
     println!("[SRVM] startup..");
     let auth = cert.auth.clone().unwrap();
-    let api = siapi::IntApi::new(
-        cert.host.clone(),
-        cert.data_port,
-        cert.file_port,
-        auth.id,
-        auth.token
-    );
-    let server_api = Server {api: api, is_connect: false, tl_poll: Instant::now()}; 
+    let mut api = siapi::IntApi::new(cert.server_addr(), auth.id, auth.token)
+        .with_fallback(cert.failover.hosts.clone());
+    if cert.tls.enabled {
+        // no fallback to plain connection, misconfigured TLS must be fixed, stay offline till then
+        match TlsConnector::new(&cert.tls) {
+            Ok(tls) => api = api.with_tls(tls),
+            Err(e) => {
+                println!("[SRVM] TLS not available, server connections refused: {:?}", e);
+                api = api.with_refused(format!("TLS not available: {}", e));
+            }
+        }
+    }
+    // neither direct connection, site may allow only proxied traffic
+    match Proxy::from_policy(&cert.proxy) {
+        Ok(Some(proxy)) => api = api.with_proxy(proxy),
+        Ok(None) => (),
+        Err(e) => {
+            println!("[SRVM] invalid proxy, server connections refused: {:?}", e);
+            api = api.with_refused(format!("invalid proxy: {}", e));
+        }
+    }
+    if cert.signing.enabled {
+        api = api.with_signing();
+    }
+    if cert.downloads.rate_limit > 0 {
+        api = api.with_download_rate(cert.downloads.rate_limit);
+    }
+    if cert.framing.enabled {
+        api = api.with_framing(Duration::from_secs(cert.framing.idle_timeout));
+    }
+    if cert.compression.data {
+        api = api.with_compression(cert.compression.threshold);
+    }
+    let server_api = Server {api, state: ConnState::Online, tl_poll: Instant::now(), tl_reprobe: Instant::now(), hello_failed: false}; 
     cmd.insert_resource(server_api);
 }
 
@@ -10,7 +10,8 @@ pub enum Startup {
 	InitIpcManager,
 	InitExecManager,
 	InitProgramUpdater,
-	InitStreamer
+	InitStreamer,
+	InitExporter
 }
 
 #[derive(StageLabel)]
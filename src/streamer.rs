@@ -1,82 +1,162 @@
-/// Stream manager, handle corresponding PollEvent(StreamReq) - 
-/// get stream from Exec, connect Exec stream with server stream, 
-/// perform transfer data for Stream.
+//! Stream manager, handle corresponding PollEvent(StreamReq) - 
+//! get stream from Exec, connect Exec stream with server stream, 
+//! perform transfer data for Stream.
+//! Handle PollEvent(ShellStream) - spawn login shell in pty if local policy allows it.
+//! Handle PollEvent(TransferStream) - send or receive file inside allowed roots.
+//! Handle PollEvent(TunnelStream) - relay stream to allowed local tcp or unix socket.
+//! Handle SupportRequest from program - open initiator stream with program console.
+//! Every session reported to server for audit, relayed sessions recorded if local policy allows it,
+//! recordings uploaded to file server by Cmd(UploadRecordings).
+//! Data of relayed sessions is pumped by dedicated threads (utils::relay), shell and tunnel
+//! streams connected to server by relay thread too, systems only start relays and watch them for close.
 
 use bevy_ecs::prelude::*;
-use std::{io::{Error, Write, Read, ErrorKind}, net::TcpStream, sync::mpsc::TryRecvError};
+use std::{io::{Error, Write, Read}, time::{Instant, Duration}};
+use std::thread::{self, JoinHandle};
 
-use crate::{stages, events, execm::{Exec, self}, data_types::{Cert, self}, utils::rmp_encode};
+use crate::{stages, events, execm::{Exec, self}, sendm::SendManager, configm::ConfigBase, srvm::Server};
+use crate::data_types::{Cert, RecordingPolicy, self, data_server::{Report, ReportType, CmdType, FEATURE_COMPRESS}, stream_api::TransferRequest};
+use crate::utils::{rmp_encode, pty::{self, Pty}, ftransfer, tunnel::LocalStream};
+use crate::utils::relay::{Relay, ChannelReader, SharedWriter};
+use crate::utils::{siapi::IntApi, tls::ServerStream};
+use crate::utils::recorder::{self, Recorder, SharedRecorder};
 
-const BUFSIZE: usize = 1024;
+const RECORDING_CLEANUP_PERIOD: Duration = Duration::from_secs(600);
 
 #[derive(Component)]
 pub struct Stream {
 	pub stream_id: i32,
 	pub program_id: i32,
-	pub tcp: TcpStream
+	pub tcp: Option<ServerStream>,		// taken by relay when program run
+	pub compressed: bool,				// negotiated when connected
+	pub relay: Option<Relay>
 }
 
 #[derive(Component)]
 pub struct StreamStateRun;
 
+#[derive(Component)]
+pub struct ShellSession {
+	pub stream_id: i32,
+	pub pty: Pty,
+	pub relay: Relay
+}
+
+#[derive(Component)]
+pub struct TunnelSession {
+	pub stream_id: i32,
+	pub target: String,
+	pub local: LocalStream,
+	pub relay: Relay
+}
+
 #[derive(Component)]
 pub struct StreamStateTransfer;
 
-fn terminator(mut cmd: Commands, execs: Query<(Entity, &Stream), (With<StreamStateTransfer>, Without<execm::Run>)>) {
+#[derive(Component)]
+pub struct FileTransfer {
+	pub stream_id: i32,
+	pub descr: String,
+	pub started: Instant,
+	pub handle: Option<JoinHandle<Result<u64, Error>>>
+}
+
+#[derive(Resource)]
+pub struct Recordings {
+	pub tl_cleanup: Instant,
+	pub upload: Option<JoinHandle<(usize, usize)>>		// uploaded, failed
+}
+
+fn audit(sm: &mut SendManager, rtype: ReportType, program_id: Option<i32>, descr: String) {
+	sm.report(Report {delay: 0, rtype, program_id, descr: Some(descr)});
+}
+
+/// Streamed programs that stopped running.
+type NotRunFilter = (With<StreamStateTransfer>, Without<execm::Run>);
+
+fn terminator(mut cmd: Commands, execs: Query<(Entity, &Stream), NotRunFilter>, mut sm: ResMut<SendManager>) {
 	for (e, s) in &execs {
+		let descr = match &s.relay {
+			Some(relay) => {
+				relay.close();
+				relay.descr()
+			},
+			None => String::new()
+		};
 		cmd.entity(e).remove::<Stream>();
 		cmd.entity(e).remove::<StreamStateTransfer>();
 		println!("[STREAMER] stream({}) for {} transfer terminated because program not run", s.stream_id, s.program_id);
+		audit(&mut sm, ReportType::StreamClose, Some(s.program_id), format!("program stream {} closed: program not run, {}", s.stream_id, descr));
 	}
 }
 
-fn transfer(mut cmd: Commands, mut execs: Query<(Entity, &Exec, &mut execm::Run, &mut Stream), With<StreamStateTransfer>>) {
-	let mut buf: [u8;BUFSIZE] = [0;BUFSIZE];
-	for (ex_e, _ex, mut run, mut s) in &mut execs {
-		let mut disonnect = false;
-		match &mut run.stdout {
-			Some(stdout) => {
-				match stdout.lock().unwrap().try_recv() {
-					Ok(data) => {
-						match s.tcp.write_all(&data) {
-							Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
-							Err(_) => disonnect = true,
-							_ => ()
-						}
-					},
-					Err(TryRecvError::Disconnected) => disonnect = true,
-					_ => ()
-				}
-			},
-			None => disonnect = true
-		}
-		match s.tcp.read(&mut buf) {
-			Ok(0) => disonnect = true,
-			Ok(len) => {
-				match &mut run.child.stdin {
-					Some(stdin) => match stdin.write_all(&buf[..len]) {
-						_ => ()
-					},
-					None => ()
-				}
-			},
-			Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
-			Err(_) => disonnect = true,
-		}
-		if disonnect {
+fn transfer(mut cmd: Commands, execs: Query<(Entity, &execm::Run, &Stream), With<StreamStateTransfer>>, mut sm: ResMut<SendManager>) {
+	for (ex_e, run, s) in &execs {
+		let reason = match &s.relay {
+			Some(relay) => relay.close_reason(),
+			None => Some("relay not started")
+		};
+		if let Some(reason) = reason {
+			run.detach();
+			let descr = match &s.relay {
+				Some(relay) => {
+					relay.close();
+					relay.descr()
+				},
+				None => String::new()
+			};
 			cmd.entity(ex_e).remove::<Stream>();
 			cmd.entity(ex_e).remove::<StreamStateTransfer>();
-			println!("[STREAMER] stream transfer terminated because program terminated or master cause");
+			println!("[STREAMER] stream({}) transfer terminated: {}", s.stream_id, reason);
+			audit(&mut sm, ReportType::StreamClose, Some(s.program_id), format!("program stream {} closed: {}, {}", s.stream_id, reason, descr));
+		}
+	}
+}
+
+fn recorder(policy: &RecordingPolicy, stream_id: i32, program_id: Option<i32>, kind: &str) -> Option<SharedRecorder> {
+	if !policy.enabled {
+		return None;
+	}
+	match Recorder::create(&policy.dir, recorder::new_header(stream_id, program_id, kind), policy.max_file_size) {
+		Ok(rec) => Some(rec),
+		Err(e) => {
+			println!("[STREAMER] fail to create recording for stream({}): {:?}", stream_id, e);
+			None
 		}
 	}
 }
 
-fn runner(mut cmd: Commands, mut evw: EventWriter<events::RunRequest>, execs: Query<(Entity, &Exec, Option<&execm::Run>), With<StreamStateRun>>) {
-	for (ex_e, ex, run) in &execs {
+fn program_relay(tcp: ServerStream, run: &execm::Run, rec: Option<SharedRecorder>, compressed: bool) -> Result<Relay, Error> {
+	let reader: Box<dyn Read + Send> = match &run.stdout {
+		Some(rx) => Box::new(ChannelReader::new(rx.clone())),
+		None => Box::new(std::io::empty())
+	};
+	let writer: Box<dyn Write + Send> = match &run.stdin {
+		Some(stdin) => Box::new(SharedWriter(stdin.clone())),
+		None => Box::new(std::io::sink())
+	};
+	run.attach();
+	Relay::spawn(tcp, reader, writer, rec, compressed)
+}
+
+fn runner(
+	mut cmd: Commands,
+	mut evw: EventWriter<events::RunRequest>,
+	mut execs: Query<(Entity, &Exec, Option<&execm::Run>, &mut Stream), With<StreamStateRun>>,
+	cert: Res<Cert>
+) {
+	for (ex_e, ex, run, mut s) in &mut execs {
 		match run {
-			Some(_) => {
+			Some(run) => {
 				cmd.entity(ex_e).remove::<StreamStateRun>();
 				cmd.entity(ex_e).insert(StreamStateTransfer);
+				if let Some(tcp) = s.tcp.take() {
+					let rec = recorder(&cert.recording, s.stream_id, Some(s.program_id), "program");
+					match program_relay(tcp, run, rec, s.compressed) {
+						Ok(relay) => s.relay = Some(relay),
+						Err(e) => println!("[STREAMER] fail to start relay for stream({}): {:?}", s.stream_id, e)
+					}
+				}
 			},
 			None => {
 				evw.send(events::RunRequest(ex.pid));
@@ -85,57 +165,453 @@ fn runner(mut cmd: Commands, mut evw: EventWriter<events::RunRequest>, execs: Qu
 	}
 }
 
+/// Program stream opened by server and support stream requested by program handled by one system,
+/// inserts are deferred, so separate systems could both stream the same program in one tick.
+/// Support streams get negative ids, allocated locally, so they are told apart from server ones in audit.
+#[allow(clippy::too_many_arguments)]
 fn adder(
 	mut cmd: Commands,
 	mut evr: EventReader<events::Stream>,
+	mut evr_support: EventReader<events::SupportRequest>,
 	execs: Query<(Entity, &Exec), Without<Stream>>,
-	cert: Res<Cert>
+	server: Res<Server>,
+	cert: Res<Cert>,
+	mut sm: ResMut<SendManager>,
+	mut last_support_id: Local<i32>
 ) {
+	let mut taken = Vec::new();
 	if !evr.is_empty() {
 		let ev = evr.iter().next().unwrap();
 		for (ex_e, ex) in &execs {
 			if ex.pid == ev.program_id {
-				let tcp = match connect(ev.id, &cert.host, cert.stream_port) {
+				let compressed = use_compression(&cert, &server.api);
+				let tcp = match connect(&server.api, ev.id, compressed) {
 					Ok(tcp) => tcp,
 					Err(e) => {
 						println!("[STREAMER] fail to connect: {:?}", e);
-						return;
+						break;
 					}
 				};
-				tcp.set_nonblocking(true).unwrap();
-				
 				cmd.entity(ex_e).insert((
 					Stream {
 						stream_id: ev.id,
 						program_id: ev.program_id,
-						tcp: tcp,
-
+						tcp: Some(tcp),
+						compressed,
+						relay: None
 					},
 					StreamStateRun
 				));
+				taken.push(ex_e);
 				println!("[STREAMER] new stream({}) for {}", ev.id, ev.program_id);
+				audit(&mut sm, ReportType::StreamOpen, Some(ev.program_id), format!("program stream {} opened", ev.id));
 				break;
 			}
 		}
 	}
+
+	for ev in evr_support.iter() {
+		let ex_e = match execs.iter().find(|(ex_e, ex)| ex.pid == ev.program_id && !taken.contains(ex_e)) {
+			Some((ex_e, _)) => ex_e,
+			None => {
+				println!("[STREAMER] support stream for {} skipped, program not found or already streamed", ev.program_id);
+				continue;
+			}
+		};
+		let compressed = use_compression(&cert, &server.api);
+		let tcp = match open(&server.api, ev.program_id, true, compressed) {
+			Ok(tcp) => tcp,
+			Err(e) => {
+				println!("[STREAMER] fail to connect: {:?}", e);
+				continue;
+			}
+		};
+		taken.push(ex_e);
+		*last_support_id = last_support_id.checked_sub(1).unwrap_or(-1);
+		let stream_id = *last_support_id;
+		cmd.entity(ex_e).insert((
+			Stream {
+				stream_id,
+				program_id: ev.program_id,
+				tcp: Some(tcp),
+				compressed,
+				relay: None
+			},
+			StreamStateRun
+		));
+		println!("[STREAMER] new support stream({}) for {}", stream_id, ev.program_id);
+		audit(&mut sm, ReportType::StreamOpen, Some(ev.program_id), format!(
+			"support stream {} requested by program: {}",
+			stream_id, ev.descr.clone().unwrap_or_default()
+		));
+	}
+}
+
+fn shell_adder(
+	mut cmd: Commands,
+	mut evr: EventReader<events::ShellStream>,
+	sessions: Query<&ShellSession>,
+	server: Res<Server>,
+	cert: Res<Cert>,
+	mut sm: ResMut<SendManager>
+) {
+	let policy = &cert.shell;
+	let mut active = sessions.iter().count();
+	for ev in evr.iter() {
+		if !policy.enabled {
+			println!("[STREAMER] shell stream({}) denied by local policy", ev.id);
+			audit(&mut sm, ReportType::StreamDenied, None, format!("shell stream {} denied: shell disabled by local policy", ev.id));
+			deny(&server.api, ev.id, String::from("shell disabled by local policy"));
+			continue;
+		}
+		if active >= policy.max_sessions {
+			println!("[STREAMER] shell stream({}) denied, sessions limit reached", ev.id);
+			audit(&mut sm, ReportType::StreamDenied, None, format!("shell stream {} denied: sessions limit {} reached", ev.id, policy.max_sessions));
+			deny(&server.api, ev.id, format!("sessions limit {} reached", policy.max_sessions));
+			continue;
+		}
+		let mut pty = match pty::spawn(&policy.shell, &policy.args) {
+			Ok(pty) => pty,
+			Err(e) => {
+				println!("[STREAMER] fail to spawn shell: {:?}", e);
+				audit(&mut sm, ReportType::StreamDenied, None, format!("shell stream {} denied: fail to spawn {}: {:?}", ev.id, policy.shell, e));
+				deny(&server.api, ev.id, format!("fail to spawn {}", policy.shell));
+				continue;
+			}
+		};
+		let compressed = use_compression(&cert, &server.api);
+		let rec = recorder(&cert.recording, ev.id, None, "shell");
+		let relay = match shell_relay(background_connect(&server.api, ev.id, compressed), &pty, rec, compressed) {
+			Ok(relay) => relay,
+			Err(e) => {
+				println!("[STREAMER] fail to start relay for shell stream({}): {:?}", ev.id, e);
+				pty.terminate();
+				continue;
+			}
+		};
+		println!("[STREAMER] new shell stream({}), pid {}", ev.id, pty.child.id());
+		audit(&mut sm, ReportType::StreamOpen, None, format!("shell stream {} opened: {} {}", ev.id, policy.shell, policy.args.join(" ")));
+		cmd.spawn(ShellSession {
+			stream_id: ev.id,
+			pty,
+			relay
+		});
+		active += 1;
+	}
+}
+
+fn shell_relay(connect: ServerConnect, pty: &Pty, rec: Option<SharedRecorder>, compressed: bool) -> Result<Relay, Error> {
+	Ok(Relay::connect(connect, Box::new(pty.master.try_clone()?), Box::new(pty.master.try_clone()?), rec, compressed))
+}
+
+fn shell_transfer(mut cmd: Commands, mut sessions: Query<(Entity, &mut ShellSession)>, cert: Res<Cert>, mut sm: ResMut<SendManager>) {
+	for (e, mut s) in &mut sessions {
+		let mut close = s.relay.close_reason();
+		if let Ok(Some(_)) = s.pty.child.try_wait() {
+			close = Some("shell exited");
+		}
+		if cert.shell.idle_timeout > 0 && s.relay.idle() >= Duration::from_secs(cert.shell.idle_timeout) {
+			close = Some("idle timeout");
+		}
+		if let Some(reason) = close {
+			s.relay.close();
+			s.pty.terminate();
+			cmd.entity(e).despawn();
+			println!("[STREAMER] shell stream({}) closed: {}", s.stream_id, reason);
+			audit(&mut sm, ReportType::StreamClose, None, format!("shell stream {} closed: {}, {}", s.stream_id, reason, s.relay.descr()));
+		}
+	}
+}
+
+fn tunnel_adder(
+	mut cmd: Commands,
+	mut evr: EventReader<events::TunnelStream>,
+	server: Res<Server>,
+	cert: Res<Cert>,
+	mut sm: ResMut<SendManager>
+) {
+	let policy = &cert.tunnel;
+	for ev in evr.iter() {
+		if !policy.enabled || !policy.targets.contains(&ev.target) {
+			println!("[STREAMER] tunnel stream({}) to {} denied by local policy", ev.id, ev.target);
+			audit(&mut sm, ReportType::StreamDenied, None, format!("tunnel stream {} to {} denied: target not allowed by local policy", ev.id, ev.target));
+			deny(&server.api, ev.id, String::from("target not allowed by local policy"));
+			continue;
+		}
+		let local = match LocalStream::connect(&ev.target) {
+			Ok(local) => local,
+			Err(e) => {
+				println!("[STREAMER] tunnel stream({}) fail to connect {}: {:?}", ev.id, ev.target, e);
+				audit(&mut sm, ReportType::StreamDenied, None, format!("tunnel stream {} to {} denied: {:?}", ev.id, ev.target, e));
+				deny(&server.api, ev.id, format!("fail to connect target: {}", e));
+				continue;
+			}
+		};
+		let compressed = use_compression(&cert, &server.api);
+		let rec = recorder(&cert.recording, ev.id, None, "tunnel");
+		let relay = match tunnel_relay(background_connect(&server.api, ev.id, compressed), &local, rec, compressed) {
+			Ok(relay) => relay,
+			Err(e) => {
+				println!("[STREAMER] fail to start relay for tunnel stream({}): {:?}", ev.id, e);
+				local.shutdown();
+				continue;
+			}
+		};
+		println!("[STREAMER] new tunnel stream({}) to {}", ev.id, ev.target);
+		audit(&mut sm, ReportType::StreamOpen, None, format!("tunnel stream {} to {} opened", ev.id, ev.target));
+		cmd.spawn(TunnelSession {
+			stream_id: ev.id,
+			target: ev.target.clone(),
+			local,
+			relay
+		});
+	}
+}
+
+fn tunnel_relay(connect: ServerConnect, local: &LocalStream, rec: Option<SharedRecorder>, compressed: bool) -> Result<Relay, Error> {
+	Ok(Relay::connect(connect, Box::new(local.try_clone()?), Box::new(local.try_clone()?), rec, compressed))
+}
+
+fn tunnel_transfer(mut cmd: Commands, sessions: Query<(Entity, &TunnelSession)>, mut sm: ResMut<SendManager>) {
+	for (e, s) in &sessions {
+		if let Some(reason) = s.relay.close_reason() {
+			s.relay.close();
+			s.local.shutdown();
+			cmd.entity(e).despawn();
+			println!("[STREAMER] tunnel stream({}) to {} closed: {}", s.stream_id, s.target, reason);
+			audit(&mut sm, ReportType::StreamClose, None, format!("tunnel stream {} to {} closed: {}, {}", s.stream_id, s.target, reason, s.relay.descr()));
+		}
+	}
 }
 
-fn connect(id: i32, host: &str, port: u16) -> Result<TcpStream, Error> {
-	let mut tcp = std::net::TcpStream::connect(format!("{}:{}", host, port))?;
-	let req_raw = rmp_encode(&data_types::stream_api::Request {id: id, initiator: false})?;
+fn ftransfer_adder(
+	mut cmd: Commands,
+	mut evr: EventReader<events::TransferStream>,
+	server: Res<Server>,
+	cert: Res<Cert>,
+	config: Res<ConfigBase>,
+	mut sm: ResMut<SendManager>
+) {
+	let policy = &cert.transfer;
+	let mut roots = policy.roots.clone();
+	if policy.program_dirs {
+		roots.push(config.bin_path.clone());
+	}
+	for ev in evr.iter() {
+		let (path, is_put) = match &ev.req {
+			TransferRequest::Get(path, _) => (path, false),
+			TransferRequest::Put(path, _, _) => (path, true)
+		};
+		let descr = format!("file {} {}", if is_put {"put"} else {"get"}, path);
+		let deny_reason = if !policy.enabled {
+			Some(String::from("transfer disabled by local policy"))
+		} else if is_put && !policy.allow_put {
+			Some(String::from("put disabled by local policy"))
+		} else {
+			None
+		};
+		let resolved = match deny_reason {
+			Some(reason) => Err(reason),
+			None => ftransfer::resolve(path, &roots).map_err(|e| e.to_string())
+		};
+		let full_path = match resolved {
+			Ok(p) => p,
+			Err(reason) => {
+				println!("[STREAMER] transfer stream({}) denied: {}", ev.id, reason);
+				audit(&mut sm, ReportType::StreamDenied, None, format!("transfer stream {} denied: {}: {}", ev.id, descr, reason));
+				deny_transfer(&server.api, ev.id, reason);
+				continue;
+			}
+		};
+
+		let req = ev.req.clone();
+		let (id, api) = (ev.id, server.api.clone());
+		let handle = thread::spawn(move || {
+			let tcp = connect(&api, id, false)?;
+			match req {
+				TransferRequest::Get(_, offset) => ftransfer::get(tcp, &full_path, offset),
+				TransferRequest::Put(_, fsize, hash) => ftransfer::put(tcp, &full_path, fsize, &hash)
+			}
+		});
+		println!("[STREAMER] new transfer stream({}): {}", ev.id, descr);
+		audit(&mut sm, ReportType::StreamOpen, None, format!("transfer stream {} opened: {}", ev.id, descr));
+		cmd.spawn(FileTransfer {
+			stream_id: ev.id,
+			descr,
+			started: Instant::now(),
+			handle: Some(handle)
+		});
+	}
+}
+
+/// Open transfer stream only to answer Denied, so server closes its session at once.
+fn deny_transfer(api: &IntApi, id: i32, reason: String) {
+	let api = api.clone();
+	thread::spawn(move || {
+		let res = connect(&api, id, false).and_then(|mut tcp| {
+			ftransfer::deny(&mut tcp, &reason)?;
+			tcp.finish_write()
+		});
+		if let Err(e) = res {
+			println!("[STREAMER] fail to send denial of transfer stream({}): {:?}", id, e);
+		}
+	});
+}
+
+fn ftransfer_checker(mut cmd: Commands, mut transfers: Query<(Entity, &mut FileTransfer)>, mut sm: ResMut<SendManager>) {
+	for (e, mut t) in &mut transfers {
+		let finished = match &t.handle {
+			Some(h) => h.is_finished(),
+			None => true
+		};
+		if !finished {
+			continue;
+		}
+		let res = match t.handle.take().map(|h| h.join()) {
+			Some(Ok(res)) => res.map_err(|e| e.to_string()),
+			_ => Err(String::from("transfer thread panicked"))
+		};
+		let result = match res {
+			Ok(bytes) => format!("done, {} B", bytes),
+			Err(e) => format!("fail: {}", e)
+		};
+		println!("[STREAMER] transfer stream({}) closed: {}", t.stream_id, result);
+		audit(&mut sm, ReportType::StreamClose, None, format!(
+			"transfer stream {} closed: {}: {}, duration {}s",
+			t.stream_id, t.descr, result, t.started.elapsed().as_secs()
+		));
+		cmd.entity(e).despawn();
+	}
+}
+
+/// Compress stream if enabled and server supports it.
+fn use_compression(cert: &Cert, api: &IntApi) -> bool {
+	cert.compression.stream && api.peer_has(FEATURE_COMPRESS)
+}
+
+fn connect(api: &IntApi, id: i32, compressed: bool) -> Result<ServerStream, Error> {
+	open(api, id, false, compressed)
+}
+
+/// Stream connect run by relay thread.
+type ServerConnect = Box<dyn FnOnce() -> Result<ServerStream, Error> + Send>;
+
+/// Connect for relay, so proxy and TLS handshakes not block main schedule.
+fn background_connect(api: &IntApi, id: i32, compressed: bool) -> ServerConnect {
+	let api = api.clone();
+	Box::new(move || connect(&api, id, compressed).map_err(|e| {
+		println!("[STREAMER] stream({}) fail to connect: {:?}", id, e);
+		e
+	}))
+}
+
+/// Answer stream opened by server with refusal instead of request, so server closes its session at once.
+/// Sent from own thread, main schedule not blocked by connect.
+fn deny(api: &IntApi, id: i32, reason: String) {
+	let api = api.clone();
+	thread::spawn(move || {
+		let res = api.connect(api.stream_port()).and_then(|mut tcp| {
+			let req = data_types::stream_api::Request {id, initiator: false};
+			tcp.write_all(&rmp_encode(&data_types::stream_api::ExtRequest::Denied(req, reason))?)?;
+			tcp.finish_write()
+		});
+		if let Err(e) = res {
+			println!("[STREAMER] fail to send denial of stream({}): {:?}", id, e);
+		}
+	});
+}
+
+fn open(api: &IntApi, id: i32, initiator: bool, compressed: bool) -> Result<ServerStream, Error> {
+	let mut tcp = api.connect(api.stream_port())?;
+	let req = data_types::stream_api::Request {id, initiator};
+	let req_raw = if compressed {
+		rmp_encode(&data_types::stream_api::ExtRequest::Compressed(req))?
+	} else {
+		rmp_encode(&req)?
+	};
 	tcp.write_all(&req_raw)?;
 	Ok(tcp)
 }
 
-fn setup(mut _cmd: Commands) {
+fn recordings_cleanup(mut recs: ResMut<Recordings>, cert: Res<Cert>) {
+	if !cert.recording.enabled || recs.tl_cleanup.elapsed() < RECORDING_CLEANUP_PERIOD {
+		return;
+	}
+	recs.tl_cleanup = Instant::now();
+	let policy = &cert.recording;
+	recorder::cleanup(&policy.dir, policy.max_total_size, Duration::from_secs(policy.retention_days * 24 * 3600));
+}
 
+fn recordings_uploader(
+	mut evr: EventReader<events::Cmd>,
+	mut recs: ResMut<Recordings>,
+	server: Res<Server>,
+	cert: Res<Cert>,
+	mut sm: ResMut<SendManager>
+) {
+	for ev in evr.iter() {
+		if !matches!(ev.ctype, CmdType::UploadRecordings) {
+			continue;
+		}
+		if recs.upload.is_some() {
+			println!("[STREAMER] recordings upload already in progress");
+			continue;
+		}
+		println!("[STREAMER] upload recordings..");
+		let api = server.api.clone();
+		let dir = cert.recording.dir.clone();
+		recs.upload = Some(thread::spawn(move || {
+			let (mut uploaded, mut failed) = (0, 0);
+			for (path, _, _) in recorder::list(&dir) {
+				match api.upload_recording(&path) {
+					Ok(()) => {
+						uploaded += 1;
+						let _ = std::fs::remove_file(&path);
+					},
+					Err(e) => {
+						println!("[STREAMER] fail to upload recording {:?}: {:?}", path, e);
+						failed += 1;
+					}
+				}
+			}
+			(uploaded, failed)
+		}));
+	}
+
+	let finished = match &recs.upload {
+		Some(h) => h.is_finished(),
+		None => false
+	};
+	if finished {
+		let descr = match recs.upload.take().unwrap().join() {
+			Ok((uploaded, failed)) => format!("uploaded {}, failed {}", uploaded, failed),
+			Err(_) => String::from("upload thread panicked")
+		};
+		println!("[STREAMER] recordings {}", descr);
+		audit(&mut sm, ReportType::RecordingsUpload, None, format!("recordings {}", descr));
+	}
+}
+
+fn setup(mut cmd: Commands, cert: Res<Cert>) {
+	if cert.recording.enabled {
+		recorder::recover(&cert.recording.dir);
+	}
+	cmd.insert_resource(Recordings {tl_cleanup: Instant::now() - RECORDING_CLEANUP_PERIOD, upload: None});
 }
 
 pub fn init(_world: &mut World, schedule: &mut Schedule) -> Result<(), Error> {
 	schedule.add_system_to_stage(stages::Startup::InitStreamer, setup);
 	schedule.add_system_to_stage(stages::Core::HandlePollEvents, adder);
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, shell_adder);
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, ftransfer_adder);
+	schedule.add_system_to_stage(stages::Core::HandlePollEvents, tunnel_adder);
 	schedule.add_system_to_stage(stages::Core::Main, runner);
 	schedule.add_system_to_stage(stages::Core::Main, transfer);
 	schedule.add_system_to_stage(stages::Core::Main, terminator);
+	schedule.add_system_to_stage(stages::Core::Main, shell_transfer);
+	schedule.add_system_to_stage(stages::Core::Main, ftransfer_checker);
+	schedule.add_system_to_stage(stages::Core::Main, tunnel_transfer);
+	schedule.add_system_to_stage(stages::Core::Main, recordings_uploader);
+	schedule.add_system_to_stage(stages::Core::Save, recordings_cleanup);
 	Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,81 @@
+use std::io::Error;
+
+use super::err;
+
+/// First byte of framed message, never used in msgpack, so plain message can't start with it.
+pub const MARKER: u8 = 0xc1;
+/// Body is zstd compressed.
+pub const FLAG_COMPRESSED: u8 = 0x01;
+/// Sender accepts compressed answer.
+pub const FLAG_ACCEPT: u8 = 0x02;
+
+const LEVEL: i32 = 3;
+const MAX_DECODED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Frame message: MARKER, flags, body (compressed if asked).
+pub fn encode(data: &[u8], compress: bool) -> Result<Vec<u8>, Error> {
+	let mut raw = vec![MARKER, FLAG_ACCEPT];
+	if compress {
+		raw[1] |= FLAG_COMPRESSED;
+		raw.extend_from_slice(&zstd::bulk::compress(data, LEVEL)?);
+	} else {
+		raw.extend_from_slice(data);
+	}
+	Ok(raw)
+}
+
+/// Message body and whether it was framed, plain messages returned as is.
+pub fn decode(raw: &[u8]) -> Result<(Vec<u8>, bool), Error> {
+	if raw.first() != Some(&MARKER) {
+		return Ok((raw.to_vec(), false));
+	}
+	let flags = *raw.get(1).ok_or_else(|| err("truncated frame header"))?;
+	let body = &raw[2..];
+	if flags & FLAG_COMPRESSED != 0 {
+		Ok((zstd::bulk::decompress(body, MAX_DECODED_SIZE)?, true))
+	} else {
+		Ok((body.to_vec(), true))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::data_types::data_server::Stat;
+
+	#[test]
+	fn compressed_round_trip() {
+		let data = vec![7;10000];
+		let raw = encode(&data, true).unwrap();
+		assert_eq!(raw[0], MARKER);
+		assert_eq!(raw[1], FLAG_ACCEPT | FLAG_COMPRESSED);
+		assert!(raw.len() < data.len());
+		assert_eq!(decode(&raw).unwrap(), (data, true));
+	}
+
+	#[test]
+	fn uncompressed_round_trip() {
+		let data = b"body".to_vec();
+		let raw = encode(&data, false).unwrap();
+		assert_eq!(raw[1], FLAG_ACCEPT);
+		assert_eq!(decode(&raw).unwrap(), (data, true));
+	}
+
+	#[test]
+	fn plain_message_passed_as_is() {
+		let data = rmp_serde::encode::to_vec(&Stat {delay: 0, name: String::from("a"), data: vec![1]}).unwrap();
+		assert_ne!(data[0], MARKER);
+		assert_eq!(decode(&data).unwrap(), (data, false));
+		assert_eq!(decode(&[]).unwrap(), (Vec::new(), false));
+	}
+
+	#[test]
+	fn truncated_header_rejected() {
+		assert!(decode(&[MARKER]).is_err());
+	}
+
+	#[test]
+	fn damaged_body_rejected() {
+		assert!(decode(&[MARKER, FLAG_COMPRESSED, 1, 2, 3]).is_err());
+	}
+}
@@ -0,0 +1,55 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+/// Write length-prefixed (u32 BE) frame.
+pub fn write_frame<W: Write>(w: &mut W, data: &[u8]) -> Result<(), Error> {
+	w.write_all(&(data.len() as u32).to_be_bytes())?;
+	w.write_all(data)
+}
+
+/// Read length-prefixed (u32 BE) frame, frames longer than max_len rejected.
+pub fn read_frame<R: Read>(r: &mut R, max_len: usize) -> Result<Vec<u8>, Error> {
+	let mut len_raw: [u8;4] = [0;4];
+	r.read_exact(&mut len_raw)?;
+	let len = u32::from_be_bytes(len_raw) as usize;
+	if len > max_len {
+		return Err(Error::new(ErrorKind::InvalidData, format!("frame too long: {}", len)));
+	}
+	let mut data = vec![0;len];
+	r.read_exact(&mut data)?;
+	Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	#[test]
+	fn frames_read_back_in_order() {
+		let mut raw = Vec::new();
+		write_frame(&mut raw, b"first").unwrap();
+		write_frame(&mut raw, &[]).unwrap();
+		write_frame(&mut raw, b"last").unwrap();
+		assert_eq!(&raw[..4], &5u32.to_be_bytes());
+		let mut r = Cursor::new(raw);
+		assert_eq!(read_frame(&mut r, 16).unwrap(), b"first");
+		assert_eq!(read_frame(&mut r, 16).unwrap(), b"");
+		assert_eq!(read_frame(&mut r, 16).unwrap(), b"last");
+		assert_eq!(read_frame(&mut r, 16).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+	}
+
+	#[test]
+	fn too_long_frame_rejected() {
+		let mut raw = Vec::new();
+		write_frame(&mut raw, &[0;17]).unwrap();
+		assert_eq!(read_frame(&mut Cursor::new(raw), 16).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn torn_frame_is_eof() {
+		let mut raw = Vec::new();
+		write_frame(&mut raw, b"data").unwrap();
+		raw.pop();
+		assert_eq!(read_frame(&mut Cursor::new(raw), 16).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+	}
+}
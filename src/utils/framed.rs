@@ -0,0 +1,200 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::data_types::data_server::{FramedHello, FramedHelloAnsw};
+use super::{err, rmp_decode, rmp_encode};
+use super::frame::{read_frame, write_frame};
+use super::tls::ServerStream;
+
+/// Sent before hello. 'S' (0x53) is positive fixint, so old server decoding plain request
+/// (enum, never bare integer) fails on it or keeps waiting, both taken as not supported.
+pub const MAGIC: &[u8;4] = b"SIMF";
+/// Framed protocol versions supported by manager, newest last.
+pub const VERSIONS: &[u16] = &[1];
+
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+const ID_SIZE: usize = 8;
+const REPROBE_PERIOD: Duration = Duration::from_secs(600);
+
+/// Negotiated persistent connection to data port.
+/// After MAGIC and hello every message is frame: u32 BE length, u64 BE request id, body.
+/// Body is the same bytes as plain request or answer.
+struct FramedConn {
+	stream: ServerStream,
+	version: u16,
+	next_id: u64,
+	tl_used: Instant
+}
+
+impl FramedConn {
+	/// None if server not supports framed protocol (closed connection, no answer in read timeout or refused versions).
+	fn open(mut stream: ServerStream) -> Result<Option<Self>, Error> {
+		let mut hello = MAGIC.to_vec();
+		write_frame(&mut hello, &rmp_encode(&FramedHello {versions: VERSIONS.to_vec()})?)?;
+		stream.write_all(&hello)?;
+		stream.flush()?;
+		let raw = match read_frame(&mut stream, MAX_FRAME_SIZE) {
+			Ok(raw) => raw,
+			// old server fails to decode hello and closes connection, or waits for rest of request
+			Err(ref e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+			Err(e) => return Err(e)
+		};
+		match rmp_decode::<FramedHelloAnsw>(&raw) {
+			Ok(FramedHelloAnsw::Ok(version)) if VERSIONS.contains(&version) => Ok(Some(Self {
+				stream,
+				version,
+				next_id: 1,
+				tl_used: Instant::now()
+			})),
+			Ok(FramedHelloAnsw::Ok(version)) => Err(err(&format!("server chose unknown version {}", version))),
+			Ok(FramedHelloAnsw::Unsupported) => Ok(None),
+			Err(_) => Ok(None)
+		}
+	}
+
+	fn request(&mut self, body: &[u8]) -> Result<Vec<u8>, Error> {
+		let id = self.next_id;
+		self.next_id += 1;
+		self.stream.write_all(&encode_request(id, body)?)?;
+		self.stream.flush()?;
+		self.tl_used = Instant::now();
+		read_answer(&mut self.stream, id)
+	}
+}
+
+/// Whole frame of request, written at once.
+fn encode_request(id: u64, body: &[u8]) -> Result<Vec<u8>, Error> {
+	let mut data = Vec::with_capacity(ID_SIZE + body.len());
+	data.extend_from_slice(&id.to_be_bytes());
+	data.extend_from_slice(body);
+	let mut buf = Vec::with_capacity(data.len() + 4);
+	write_frame(&mut buf, &data)?;
+	Ok(buf)
+}
+
+/// Body of answer to request id, answers to earlier requests given up on are skipped.
+fn read_answer<R: Read>(r: &mut R, id: u64) -> Result<Vec<u8>, Error> {
+	loop {
+		let raw = read_frame(r, MAX_FRAME_SIZE)?;
+		if raw.len() < ID_SIZE {
+			return Err(Error::new(ErrorKind::InvalidData, "frame without request id"));
+		}
+		let mut id_raw = [0;ID_SIZE];
+		id_raw.copy_from_slice(&raw[..ID_SIZE]);
+		if u64::from_be_bytes(id_raw) == id {
+			return Ok(raw[ID_SIZE..].to_vec());
+		}
+	}
+}
+
+/// Framed mode of data port: one connection kept open between requests and
+/// reopened on failure. Falls back to plain requests if server not supports it,
+/// framed protocol offered again after REPROBE_PERIOD.
+pub struct Framed {
+	conn: Option<FramedConn>,
+	tl_refused: Option<Instant>,
+	idle_timeout: Duration
+}
+
+impl Framed {
+	pub fn new(idle_timeout: Duration) -> Self {
+		Self {conn: None, tl_refused: None, idle_timeout}
+	}
+
+	/// Drop kept connection and refusal, server changed.
+	pub fn reset(&mut self) {
+		self.conn = None;
+		self.tl_refused = None;
+	}
+
+	/// Answer body, None if server not supports framed protocol.
+	/// Request retried once on new connection if kept one turned out broken,
+	/// so server may get it twice.
+	pub fn request<F>(&mut self, connect: F, body: &[u8]) -> Result<Option<Vec<u8>>, Error>
+	where F: Fn() -> Result<ServerStream, Error> {
+		if let Some(tl) = self.tl_refused {
+			if tl.elapsed() < REPROBE_PERIOD {
+				return Ok(None);
+			}
+			self.tl_refused = None;
+		}
+		if let Some(conn) = &self.conn {
+			if conn.tl_used.elapsed() > self.idle_timeout {
+				self.conn = None;
+			}
+		}
+		if let Some(conn) = &mut self.conn {
+			match conn.request(body) {
+				Ok(answ) => return Ok(Some(answ)),
+				Err(e) => {
+					println!("[FRAMED] kept connection broken, reconnect: {:?}", e);
+					self.conn = None;
+				}
+			}
+		}
+		let mut conn = match FramedConn::open(connect()?)? {
+			Some(conn) => conn,
+			None => {
+				println!("[FRAMED] server not supports framed protocol, use plain requests");
+				self.tl_refused = Some(Instant::now());
+				return Ok(None);
+			}
+		};
+		println!("[FRAMED] connected, protocol version {}", conn.version);
+		let answ = conn.request(body)?;
+		self.conn = Some(conn);
+		Ok(Some(answ))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	#[test]
+	fn request_frame_layout() {
+		let raw = encode_request(0x0102, b"body").unwrap();
+		assert_eq!(&raw[..4], &12u32.to_be_bytes());
+		assert_eq!(&raw[4..12], &0x0102u64.to_be_bytes());
+		assert_eq!(&raw[12..], b"body");
+	}
+
+	#[test]
+	fn answer_of_request_read_back() {
+		let raw = encode_request(5, b"answ").unwrap();
+		assert_eq!(read_answer(&mut Cursor::new(raw), 5).unwrap(), b"answ");
+	}
+
+	#[test]
+	fn stale_answers_skipped() {
+		let mut raw = encode_request(3, b"old").unwrap();
+		raw.extend(encode_request(4, b"older").unwrap());
+		raw.extend(encode_request(5, b"answ").unwrap());
+		raw.extend(encode_request(6, b"next").unwrap());
+		let mut r = Cursor::new(raw);
+		assert_eq!(read_answer(&mut r, 5).unwrap(), b"answ");
+		assert_eq!(read_answer(&mut r, 6).unwrap(), b"next");
+	}
+
+	#[test]
+	fn frame_without_id_rejected() {
+		let mut raw = Vec::new();
+		write_frame(&mut raw, &[1, 2, 3]).unwrap();
+		assert_eq!(read_answer(&mut Cursor::new(raw), 1).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn closed_before_answer() {
+		let raw = encode_request(5, b"answ").unwrap();
+		let mut r = Cursor::new(raw[..raw.len() - 1].to_vec());
+		assert_eq!(read_answer(&mut r, 5).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+	}
+
+	#[test]
+	fn magic_not_plain_request() {
+		// plain request is msgpack enum, never bare positive fixint
+		assert!(MAGIC[0] <= 0x7f);
+		assert!(rmp_decode::<crate::data_types::data_server::Request>(MAGIC).is_err());
+	}
+}
@@ -0,0 +1,206 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::data_types::stream_api::{TransferAnsw, TransferResult};
+use super::{err, mos, rmp_encode};
+use super::frame::{read_frame, write_frame};
+use super::tls::ServerStream;
+
+pub const CHUNK_SIZE: usize = 65536;
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+const PART_EXT: &str = "part";
+const HASH_EXT: &str = "hash";
+
+/// Resolve path to absolute and check that it lies inside one of roots.
+pub fn resolve(path: &str, roots: &[String]) -> Result<PathBuf, Error> {
+	let path = Path::new(path);
+	if !path.is_absolute() {
+		return Err(err("path must be absolute"));
+	}
+	let full = if path.exists() {
+		path.canonicalize()?
+	} else {
+		let (parent, name) = match (path.parent(), path.file_name()) {
+			(Some(parent), Some(name)) => (parent, name),
+			_ => return Err(err("invalid path"))
+		};
+		parent.canonicalize()?.join(name)
+	};
+	for r in roots {
+		if let Ok(root) = Path::new(r).canonicalize() {
+			if full.starts_with(&root) {
+				return Ok(full);
+			}
+		}
+	}
+	Err(err("path outside of allowed roots"))
+}
+
+pub fn deny(tcp: &mut ServerStream, reason: &str) -> Result<(), Error> {
+	write_frame(tcp, &rmp_encode(&TransferAnsw::Denied(String::from(reason)))?)
+}
+
+/// Send file to server starting from offset, return number of sent bytes.
+pub fn get(mut tcp: ServerStream, path: &Path, offset: u64) -> Result<u64, Error> {
+	set_timeouts(&tcp)?;
+	let mut file = File::open(path)?;
+	if !file.metadata()?.is_file() {
+		deny(&mut tcp, "not a file")?;
+		return Err(err("not a file"));
+	}
+	// file may grow while sending (logs), so size is what was hashed
+	let hash = mos::hash_file(&mut file)?;
+	let fsize = file.stream_position()?;
+	let offset = offset.min(fsize);
+	write_frame(&mut tcp, &rmp_encode(&TransferAnsw::Ready(fsize, offset, hash))?)?;
+
+	file.seek(SeekFrom::Start(offset))?;
+	let mut data = file.take(fsize - offset);
+	let mut buf = vec![0;CHUNK_SIZE];
+	let mut sent = 0;
+	loop {
+		let len = data.read(&mut buf)?;
+		if len == 0 {
+			break;
+		}
+		write_frame(&mut tcp, &buf[..len])?;
+		sent += len as u64;
+	}
+	write_frame(&mut tcp, &[])?;
+	Ok(sent)
+}
+
+/// Receive file from server into partial file, resume from already received part
+/// if it is part of file with the same hash (kept next to it), verify size and hash and move to path.
+/// Return number of received bytes.
+pub fn put(mut tcp: ServerStream, path: &Path, fsize: u64, hash: &[u8]) -> Result<u64, Error> {
+	set_timeouts(&tcp)?;
+	let part = part_path(path);
+	let part_hash = part.with_extension(format!("{}.{}", PART_EXT, HASH_EXT));
+	let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&part)?;
+	let mut offset = file.metadata()?.len();
+	// partial of other file - start over
+	if offset > fsize || fs::read(&part_hash).ok().as_deref() != Some(hash) {
+		offset = 0;
+	}
+	file.set_len(offset)?;
+	fs::write(&part_hash, hash)?;
+	file.seek(SeekFrom::Start(offset))?;
+	write_frame(&mut tcp, &rmp_encode(&TransferAnsw::Ready(fsize, offset, hash.to_vec()))?)?;
+
+	let mut received = 0;
+	loop {
+		let chunk = read_frame(&mut tcp, CHUNK_SIZE)?;
+		if chunk.is_empty() {
+			break;
+		}
+		if offset + chunk.len() as u64 > fsize {
+			return fail(&mut tcp, "size exceeded");
+		}
+		file.write_all(&chunk)?;
+		offset += chunk.len() as u64;
+		received += chunk.len() as u64;
+	}
+	if offset != fsize {
+		return fail(&mut tcp, "size mismatch");
+	}
+	if mos::hash_file(&mut file)? != hash {
+		drop(file);
+		fs::remove_file(&part)?;
+		let _ = fs::remove_file(&part_hash);
+		return fail(&mut tcp, "integrity error");
+	}
+	file.sync_all()?;
+	fs::rename(&part, path)?;
+	let _ = fs::remove_file(&part_hash);
+	write_frame(&mut tcp, &rmp_encode(&TransferResult::Ok)?)?;
+	Ok(received)
+}
+
+fn fail(tcp: &mut ServerStream, reason: &str) -> Result<u64, Error> {
+	write_frame(tcp, &rmp_encode(&TransferResult::Fail(String::from(reason)))?)?;
+	Err(err(reason))
+}
+
+fn part_path(path: &Path) -> PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_os_string();
+	name.push(".");
+	name.push(PART_EXT);
+	path.with_file_name(name)
+}
+
+fn set_timeouts(tcp: &ServerStream) -> Result<(), Error> {
+	tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+	tcp.set_write_timeout(Some(IO_TIMEOUT))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::symlink;
+	use crate::utils::test_dir;
+
+	fn roots(dir: &Path) -> Vec<String> {
+		vec![dir.join("root").to_string_lossy().to_string()]
+	}
+
+	fn setup(name: &str) -> PathBuf {
+		let dir = test_dir(name);
+		fs::create_dir_all(dir.join("root/sub")).unwrap();
+		fs::create_dir_all(dir.join("outside")).unwrap();
+		fs::write(dir.join("root/sub/file"), b"data").unwrap();
+		fs::write(dir.join("outside/file"), b"data").unwrap();
+		dir
+	}
+
+	#[test]
+	fn relative_path_rejected() {
+		let dir = setup("resolve_relative");
+		assert!(resolve("root/sub/file", &roots(&dir)).is_err());
+	}
+
+	#[test]
+	fn file_inside_root() {
+		let dir = setup("resolve_inside");
+		let path = dir.join("root/sub/file");
+		assert_eq!(resolve(&path.to_string_lossy(), &roots(&dir)).unwrap(), path.canonicalize().unwrap());
+	}
+
+	#[test]
+	fn new_file_inside_root() {
+		let dir = setup("resolve_new");
+		let path = dir.join("root/sub/new");
+		assert_eq!(resolve(&path.to_string_lossy(), &roots(&dir)).unwrap(), dir.join("root/sub").canonicalize().unwrap().join("new"));
+	}
+
+	#[test]
+	fn outside_root_rejected() {
+		let dir = setup("resolve_outside");
+		assert!(resolve(&dir.join("outside/file").to_string_lossy(), &roots(&dir)).is_err());
+		assert!(resolve(&dir.join("outside/new").to_string_lossy(), &roots(&dir)).is_err());
+	}
+
+	#[test]
+	fn dot_dot_escape_rejected() {
+		let dir = setup("resolve_dot_dot");
+		assert!(resolve(&dir.join("root/sub/../../outside/file").to_string_lossy(), &roots(&dir)).is_err());
+		assert!(resolve(&dir.join("root/../outside/new").to_string_lossy(), &roots(&dir)).is_err());
+	}
+
+	#[test]
+	fn symlink_escape_rejected() {
+		let dir = setup("resolve_symlink");
+		symlink(dir.join("outside"), dir.join("root/link")).unwrap();
+		assert!(resolve(&dir.join("root/link/file").to_string_lossy(), &roots(&dir)).is_err());
+		assert!(resolve(&dir.join("root/link/new").to_string_lossy(), &roots(&dir)).is_err());
+	}
+
+	#[test]
+	fn prefix_of_root_name_rejected() {
+		let dir = setup("resolve_prefix");
+		fs::create_dir_all(dir.join("root2")).unwrap();
+		assert!(resolve(&dir.join("root2/new").to_string_lossy(), &roots(&dir)).is_err());
+	}
+}
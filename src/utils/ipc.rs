@@ -22,10 +22,17 @@ pub struct Log {
 	pub module: ModuleStatus
 }
 
+#[derive(Deserialize, Clone)]
+pub struct Support {
+	pub name: String,
+	pub descr: Option<String>
+}
+
 #[derive(Deserialize)]
 pub enum RequestFromProgram {
 	Log(Log),			// -> ResAnsw
-	Stat(Stat)			// -> ResAnsw
+	Stat(Stat),			// -> ResAnsw
+	Support(Support)	// -> ResAnsw, open support stream with program console
 }
 
 #[derive(Deserialize, Serialize)]
@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+
+use super::{rmp_decode, rmp_encode};
+use super::frame::{read_frame, write_frame};
+
+const MAX_RECORD_SIZE: usize = 16 * 1024 * 1024;
+const COMPACT_EXT: &str = "compact";
+const CORRUPT_EXT: &str = "corrupt";
+const MARK_EXT: &str = "mark";
+const MARK_TMP_EXT: &str = "mark.tmp";
+/// Ids reserved ahead by one mark write, unix micros of clock time (one minute).
+const MARK_RESERVE: u64 = 60_000_000;
+
+#[derive(Serialize, Deserialize)]
+enum JournalRecord {
+	Add(u64, i64, Vec<u8>),		// id, dt (unix millis), encoded item
+	Ack(u64)					// id
+}
+
+/// Append-only write-ahead journal of items waiting for acknowledge.
+/// Add record is synced to disk before add returns, ack only appended,
+/// so after crash item may be sent twice but never lost.
+/// Live items kept in memory in id order, file rewritten with them on compact.
+/// High-water mark of issued ids kept in file next to journal and reserved ahead,
+/// so ids keep increasing after restart even with empty journal and clock gone back.
+pub struct Journal {
+	path: PathBuf,
+	file: File,
+	live: BTreeMap<u64, (i64, Vec<u8>)>,
+	acked: usize,
+	mark: u64
+}
+
+impl Journal {
+	/// Open journal and replay it, torn record at the end (crash while writing) dropped.
+	/// Damaged records skipped, if journal damaged in the middle its copy kept aside before compact.
+	pub fn open(path: &str) -> Result<Self, Error> {
+		let path = PathBuf::from(path);
+		if let Some(dir) = path.parent() {
+			if !dir.as_os_str().is_empty() {
+				fs::create_dir_all(dir)?;
+			}
+		}
+		let mut live = BTreeMap::new();
+		let mut acked = 0;
+		let mut mark = read_mark(&path.with_extension(MARK_EXT));
+		let mut corrupted = false;
+		if path.exists() {
+			let mut reader = BufReader::new(File::open(&path)?);
+			loop {
+				let raw = match read_frame(&mut reader, MAX_RECORD_SIZE) {
+					Ok(raw) => raw,
+					Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+					Err(e) => {
+						// length damaged, records after it can't be found
+						println!("[JOURNAL] {:?} corrupted, replay stopped: {:?}", path, e);
+						corrupted = true;
+						break;
+					}
+				};
+				match rmp_decode::<JournalRecord>(&raw) {
+					Ok(JournalRecord::Add(id, dt, data)) => {
+						mark = mark.max(id);
+						live.insert(id, (dt, data));
+					},
+					Ok(JournalRecord::Ack(id)) => {
+						mark = mark.max(id);
+						live.remove(&id);
+						acked += 1;
+					},
+					Err(e) => {
+						println!("[JOURNAL] {:?} damaged record skipped: {:?}", path, e);
+						corrupted = true;
+					}
+				}
+			}
+		}
+		if corrupted {
+			let backup = path.with_extension(format!("{}.{}", CORRUPT_EXT, Utc::now().timestamp()));
+			fs::copy(&path, &backup)?;
+			println!("[JOURNAL] copy of corrupted journal kept in {:?}", backup);
+		}
+		let file = OpenOptions::new().append(true).create(true).open(&path)?;
+		let mut journal = Self {path, file, live, acked, mark};
+		// drop acked records, torn tail and damaged records right away
+		journal.compact()?;
+		Ok(journal)
+	}
+
+	/// Highest id may be issued before, new ids must be above it.
+	pub fn last_id(&self) -> u64 {
+		self.mark
+	}
+
+	/// Make sure issued id is below persisted mark, mark moved ahead by reserve when reached.
+	pub fn reserve_id(&mut self, id: u64) -> Result<(), Error> {
+		if id <= self.mark {
+			return Ok(());
+		}
+		let mark = id.saturating_add(MARK_RESERVE);
+		let tmp = self.path.with_extension(MARK_TMP_EXT);
+		let mut file = File::create(&tmp)?;
+		file.write_all(&mark.to_le_bytes())?;
+		file.sync_all()?;
+		fs::rename(&tmp, self.path.with_extension(MARK_EXT))?;
+		sync_dir(&self.path);
+		self.mark = mark;
+		Ok(())
+	}
+
+	pub fn add(&mut self, id: u64, dt: i64, data: Vec<u8>) -> Result<(), Error> {
+		self.append(&JournalRecord::Add(id, dt, data.clone()))?;
+		self.file.sync_data()?;
+		self.live.insert(id, (dt, data));
+		Ok(())
+	}
+
+	pub fn ack(&mut self, id: u64) -> Result<(), Error> {
+		if self.live.remove(&id).is_none() {
+			return Ok(());
+		}
+		self.acked += 1;
+		self.append(&JournalRecord::Ack(id))
+	}
+
+	/// Live items in id order: (id, dt, data).
+	pub fn items(&self) -> impl Iterator<Item = (u64, i64, &Vec<u8>)> {
+		self.live.iter().map(|(id, (dt, data))| (*id, *dt, data))
+	}
+
+	pub fn len(&self) -> usize {
+		self.live.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.live.is_empty()
+	}
+
+	/// Count of ack records since last compact.
+	pub fn acked(&self) -> usize {
+		self.acked
+	}
+
+	/// Rewrite journal with live items only, new file synced and renamed over old one.
+	pub fn compact(&mut self) -> Result<(), Error> {
+		let tmp = self.path.with_extension(COMPACT_EXT);
+		let mut w = BufWriter::new(File::create(&tmp)?);
+		for (id, (dt, data)) in &self.live {
+			write_frame(&mut w, &rmp_encode(&JournalRecord::Add(*id, *dt, data.clone()))?)?;
+		}
+		let file = w.into_inner().map_err(|e| e.into_error())?;
+		file.sync_all()?;
+		fs::rename(&tmp, &self.path)?;
+		sync_dir(&self.path);
+		self.file = OpenOptions::new().append(true).open(&self.path)?;
+		self.acked = 0;
+		Ok(())
+	}
+
+	fn append(&mut self, rec: &JournalRecord) -> Result<(), Error> {
+		// one write per record, so crash leaves at most one torn record at the end
+		let raw = rmp_encode(rec)?;
+		let mut buf = Vec::with_capacity(raw.len() + 4);
+		write_frame(&mut buf, &raw)?;
+		self.file.write_all(&buf)
+	}
+}
+
+/// Persisted mark, 0 if missing or damaged.
+fn read_mark(path: &Path) -> u64 {
+	match fs::read(path) {
+		Ok(raw) => match <[u8;8]>::try_from(raw.as_slice()) {
+			Ok(raw) => u64::from_le_bytes(raw),
+			Err(_) => {
+				println!("[JOURNAL] {:?} damaged, ignored", path);
+				0
+			}
+		},
+		Err(_) => 0
+	}
+}
+
+fn sync_dir(path: &Path) {
+	if let Some(dir) = path.parent() {
+		let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+		if let Ok(d) = File::open(dir) {
+			let _ = d.sync_all();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Seek;
+	use crate::utils::test_dir;
+
+	fn open(dir: &Path) -> Journal {
+		Journal::open(&dir.join("journal").to_string_lossy()).unwrap()
+	}
+
+	fn items(j: &Journal) -> Vec<(u64, i64, Vec<u8>)> {
+		j.items().map(|(id, dt, data)| (id, dt, data.clone())).collect()
+	}
+
+	#[test]
+	fn replay_in_id_order_without_acked() {
+		let dir = test_dir("journal_replay");
+		let mut j = open(&dir);
+		j.add(3, 30, vec![3]).unwrap();
+		j.add(1, 10, vec![1]).unwrap();
+		j.add(2, 20, vec![2]).unwrap();
+		j.ack(1).unwrap();
+		drop(j);
+		let j = open(&dir);
+		assert_eq!(items(&j), vec![(2, 20, vec![2]), (3, 30, vec![3])]);
+	}
+
+	#[test]
+	fn torn_tail_dropped() {
+		let dir = test_dir("journal_torn");
+		let mut j = open(&dir);
+		j.add(1, 10, vec![1]).unwrap();
+		j.add(2, 20, vec![2]).unwrap();
+		drop(j);
+		let path = dir.join("journal");
+		let len = fs::metadata(&path).unwrap().len();
+		OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+		let j = open(&dir);
+		assert_eq!(items(&j), vec![(1, 10, vec![1])]);
+		assert!(!corrupt_copies(&dir));
+	}
+
+	#[test]
+	fn damaged_record_skipped_and_kept_aside() {
+		let dir = test_dir("journal_damaged");
+		let mut j = open(&dir);
+		j.add(1, 10, vec![1]).unwrap();
+		drop(j);
+		let path = dir.join("journal");
+		let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+		write_frame(&mut file, &[0xc1, 0xc1]).unwrap();
+		drop(file);
+		let mut j = open(&dir);
+		j.add(2, 20, vec![2]).unwrap();
+		drop(j);
+		let j = open(&dir);
+		assert_eq!(items(&j), vec![(1, 10, vec![1]), (2, 20, vec![2])]);
+		assert!(corrupt_copies(&dir));
+	}
+
+	#[test]
+	fn compact_keeps_live_items_only() {
+		let dir = test_dir("journal_compact");
+		let mut j = open(&dir);
+		for id in 1..=10 {
+			j.add(id, id as i64, vec![0;100]).unwrap();
+		}
+		for id in 1..=9 {
+			j.ack(id).unwrap();
+		}
+		assert_eq!(j.acked(), 9);
+		let before = fs::metadata(dir.join("journal")).unwrap().len();
+		j.compact().unwrap();
+		assert_eq!(j.acked(), 0);
+		assert!(fs::metadata(dir.join("journal")).unwrap().len() < before / 5);
+		j.add(11, 11, vec![11]).unwrap();
+		drop(j);
+		let j = open(&dir);
+		assert_eq!(j.items().map(|(id, _, _)| id).collect::<Vec<u64>>(), vec![10, 11]);
+	}
+
+	#[test]
+	fn ack_of_unknown_id_ignored() {
+		let dir = test_dir("journal_unknown_ack");
+		let mut j = open(&dir);
+		j.ack(5).unwrap();
+		assert_eq!(j.acked(), 0);
+		let mut file = File::open(dir.join("journal")).unwrap();
+		assert_eq!(file.seek(std::io::SeekFrom::End(0)).unwrap(), 0);
+	}
+
+	#[test]
+	fn mark_persisted_and_reserved_ahead() {
+		let dir = test_dir("journal_mark");
+		let mut j = open(&dir);
+		assert_eq!(j.last_id(), 0);
+		j.reserve_id(100).unwrap();
+		assert_eq!(j.last_id(), 100 + MARK_RESERVE);
+		// within reserve no write needed
+		j.reserve_id(200).unwrap();
+		assert_eq!(j.last_id(), 100 + MARK_RESERVE);
+		j.add(100, 0, vec![]).unwrap();
+		j.ack(100).unwrap();
+		j.compact().unwrap();
+		drop(j);
+		let j = open(&dir);
+		assert!(j.is_empty());
+		assert_eq!(j.last_id(), 100 + MARK_RESERVE);
+	}
+
+	#[test]
+	fn mark_raised_by_replayed_ids() {
+		let dir = test_dir("journal_mark_replay");
+		let mut j = open(&dir);
+		// id not reserved, no mark written
+		j.add(5, 0, vec![]).unwrap();
+		drop(j);
+		assert!(!dir.join("journal").with_extension(MARK_EXT).exists());
+		assert_eq!(open(&dir).last_id(), 5);
+	}
+
+	fn corrupt_copies(dir: &Path) -> bool {
+		fs::read_dir(dir).unwrap().flatten().any(|e| e.file_name().to_string_lossy().contains(CORRUPT_EXT))
+	}
+}
@@ -8,6 +8,19 @@ use serde::Serialize;
 pub mod mos;
 pub mod siapi;
 pub mod ipc;
+pub mod pty;
+pub mod frame;
+pub mod ftransfer;
+pub mod tunnel;
+pub mod relay;
+pub mod recorder;
+pub mod journal;
+pub mod compress;
+pub mod tls;
+pub mod framed;
+pub mod sign;
+pub mod ratelimit;
+pub mod proxy;
 
 pub fn err(e: &str) -> Error {
 	Error::new(ErrorKind::Other, e)
@@ -48,4 +61,13 @@ where T: IntoIterator {
 
 pub fn some_str(val: &str) -> Option<String> {
 	Some(format!("{}", val))
+}
+
+/// Empty dir for test, unique for test name and process.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+	let dir = std::env::temp_dir().join(format!("manager_test_{}_{}", name, std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	dir
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, BufReader, ErrorKind};
 use std::path::{PathBuf, Path};
 use std::process::{Command};
 use std::thread;
@@ -29,6 +29,13 @@ const TEMP_SEND_DATA_PATH: &str = "./temp_send_data";
 const ARCH_TYPE: &str = "tar.zst";
 
 const HASH_CALC_BUFFER_SIZE: usize = 4096;
+/// Patch window covers whole base, highest window zstd accepts on target.
+#[cfg(target_pointer_width = "64")]
+const PATCH_WINDOW_LOG_MAX: u32 = 31;
+#[cfg(not(target_pointer_width = "64"))]
+const PATCH_WINDOW_LOG_MAX: u32 = 30;
+/// Base held in memory about twice while patched (dictionary and window), builds with bigger base downloaded in full.
+const PATCH_MAX_BASE_SIZE: u64 = 256 * 1024 * 1024;
 
 fn unpack(arch: &str, to: &str) -> Result<(), Error> {
     let tar_zstd = File::open(arch)?;
@@ -47,11 +54,16 @@ pub fn read_cert() -> Result<Cert, Error> {
 	}
 }
 
+/// Cert written to temp file and renamed over old one, so crash never leaves it half written.
 pub fn write_cert(cert: &Cert) -> Result<(), Error> {
 	let res = serde_json::to_vec_pretty(cert);
 	match res {
 		Ok(data) => {
-			fs::write(CERT_PATH, data)?;
+			let tmp = format!("{}.tmp", CERT_PATH);
+			let mut file = File::create(&tmp)?;
+			file.write_all(&data)?;
+			file.sync_all()?;
+			fs::rename(&tmp, CERT_PATH)?;
 			Ok(())
 		},
 		Err(e) => Err(err(&e.to_string()))
@@ -338,7 +350,8 @@ pub fn setup_config(name: &str, config_path: &str, data: &Vec<u8>, bin_path: &st
 	file.write_all(&data[..])
 }
 
-pub fn create_temp_arch(name: &str) -> Result<File, Error> {
+/// Temp arch opened as is, partial file left by failed download kept for resume.
+pub fn open_temp_arch(name: &str) -> Result<File, Error> {
 	let parent_path = PathBuf::from(TEMP_ARCH_PATH);
 	if !parent_path.exists() {
 		fs::create_dir_all(parent_path)?;
@@ -347,28 +360,78 @@ pub fn create_temp_arch(name: &str) -> Result<File, Error> {
 	opt.write(true);
 	opt.read(true);
 	opt.create(true);
-	opt.append(false);
-	opt.truncate(true);
+	opt.truncate(false);
 	opt.open(format_temp_arch_path(name))
 }
 
-pub fn temp_send_data_push(id: i64, data: &Vec<u8>) -> Result<(), Error> {
-	let file_path = format!("{}/{}", TEMP_SEND_DATA_PATH, id);
-	let mut opt = OpenOptions::new();
-	opt.write(true);
-	opt.create(true);
-	opt.truncate(true);
-	if !Path::new(TEMP_SEND_DATA_PATH).exists() {
-		fs::create_dir(TEMP_SEND_DATA_PATH)?;
+/// Hash of file temp arch is (part of), None if not known.
+pub fn read_temp_arch_hash(name: &str) -> Option<Vec<u8>> {
+	fs::read(format!("{}.hash", format_temp_arch_path(name))).ok()
+}
+
+pub fn write_temp_arch_hash(name: &str, hash: &[u8]) -> Result<(), Error> {
+	fs::write(format!("{}.hash", format_temp_arch_path(name)), hash)
+}
+
+pub fn remove_temp_arch(name: &str) -> Result<(), Error> {
+	let _ = fs::remove_file(format!("{}.hash", format_temp_arch_path(name)));
+	fs::remove_file(format_temp_arch_path(name))
+}
+
+/// Temp arch exists, is whole file with hash and not too big to be patched,
+/// temp arch of installed build is kept as base for patches.
+pub fn is_patch_base(name: &str, hash: &[u8]) -> Result<bool, Error> {
+	match File::open(format_temp_arch_path(name)) {
+		Ok(mut file) => Ok(file.metadata()?.len() <= PATCH_MAX_BASE_SIZE && hash_file(&mut file)? == hash),
+		Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(false),
+		Err(e) => Err(e)
 	}
-	let mut file = opt.open(&file_path)?;
-	file.write_all(&data)?;
-	std::thread::sleep(std::time::Duration::from_millis(2));
-	Ok(())
 }
 
+/// Replace temp arch base with result of zstd patch-from (base as raw dictionary) in temp arch patch.
+/// Base kept if result has other hash.
+pub fn apply_patch(base: &str, patch: &str, hash: &[u8]) -> Result<(), Error> {
+	let base_path = format_temp_arch_path(base);
+	if fs::metadata(&base_path)?.len() > PATCH_MAX_BASE_SIZE {
+		return Err(err("base too big for patch"));
+	}
+	// dictionary copied into decoder, base data not kept
+	let mut decoder = Decoder::with_dictionary(BufReader::new(File::open(format_temp_arch_path(patch))?), &fs::read(&base_path)?)?;
+	// patch-from window covers whole base
+	decoder.window_log_max(PATCH_WINDOW_LOG_MAX)?;
+	let tmp_path = format!("{}.patched", base_path);
+	let mut tmp = File::create(&tmp_path)?;
+	let mut context = Context::new(&SHA256);
+	let mut buf = [0;8192];
+	loop {
+		let len = match decoder.read(&mut buf) {
+			Ok(len) => len,
+			Err(e) => {
+				let _ = fs::remove_file(&tmp_path);
+				return Err(e);
+			}
+		};
+		if len == 0 {
+			break;
+		}
+		context.update(&buf[..len]);
+		tmp.write_all(&buf[..len])?;
+	}
+	if context.finish().as_ref() != hash {
+		let _ = fs::remove_file(&tmp_path);
+		return Err(err("patched build hash mismatch"));
+	}
+	tmp.sync_all()?;
+	fs::rename(&tmp_path, &base_path)?;
+	write_temp_arch_hash(base, hash)
+}
+
+/// Oldest item saved by versions without send journal.
 pub fn temp_send_data_pop() -> Result<Option<(i64,Vec<u8>)>, Error> {
 	let mut files = Vec::<(PathBuf, i64)>::new();
+	if !Path::new(TEMP_SEND_DATA_PATH).exists() {
+		return Ok(None);
+	}
 	let entries = fs::read_dir(TEMP_SEND_DATA_PATH)?;
 
 	for entry in entries {
@@ -398,7 +461,7 @@ pub fn temp_send_data_pop() -> Result<Option<(i64,Vec<u8>)>, Error> {
 
 	if files.len() > 0 {
 		let mut min_id_i: usize = 0;
-		let mut min_val: i64 = 0;
+		let mut min_val: i64 = files[0].1;
 		for i in 0..files.len() {
 			if files[i].1 < min_val {
 				min_id_i = i;
@@ -0,0 +1,333 @@
+use std::env;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use crate::data_types::ProxyPolicy;
+use super::err;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEAD_SIZE: usize = 8192;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USER_PASS: u8 = 2;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+/// Plain TCP connect, host resolved on every call, all addresses tried.
+pub fn connect_tcp(host: &str, port: u16) -> Result<TcpStream, Error> {
+	let mut last_err = err("host not resolved");
+	for addr in (host, port).to_socket_addrs()? {
+		match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+			Ok(tcp) => return Ok(tcp),
+			Err(e) => last_err = e
+		}
+	}
+	Err(last_err)
+}
+
+#[derive(PartialEq)]
+enum ProxyKind {
+	Http,
+	Socks5,			// server host resolved locally
+	Socks5h			// server host resolved by proxy
+}
+
+/// Proxy for server connections: HTTP CONNECT or SOCKS5, optionally with credentials.
+/// Server host resolved by proxy, except of plain socks5.
+pub struct Proxy {
+	kind: ProxyKind,
+	host: String,
+	port: u16,
+	credentials: Option<(String, String)>,
+	no_proxy: Vec<String>
+}
+
+impl Proxy {
+	/// Proxy from policy url, else from ALL_PROXY / HTTPS_PROXY / HTTP_PROXY if allowed, None - direct.
+	/// Invalid policy url is error, invalid or unsupported environment proxy only skipped.
+	pub fn from_policy(policy: &ProxyPolicy) -> Result<Option<Self>, Error> {
+		Self::from_vars(policy, |name| env::var(name).ok())
+	}
+
+	fn from_vars<F: Fn(&str) -> Option<String>>(policy: &ProxyPolicy, var: F) -> Result<Option<Self>, Error> {
+		if let Some(url) = &policy.url {
+			return Ok(Some(Self::parse(url, policy.no_proxy.clone())?));
+		}
+		if !policy.use_env {
+			return Ok(None);
+		}
+		let mut no_proxy = policy.no_proxy.clone();
+		for name in ["NO_PROXY", "no_proxy"] {
+			if let Some(val) = var(name) {
+				no_proxy.extend(val.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()));
+			}
+		}
+		for name in ["ALL_PROXY", "all_proxy", "HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"] {
+			let url = match var(name) {
+				Some(url) if !url.is_empty() => url,
+				_ => continue
+			};
+			match Self::parse(&url, no_proxy.clone()) {
+				Ok(proxy) => return Ok(Some(proxy)),
+				Err(e) => println!("[PROXY] {} skipped: {:?}", name, e)
+			}
+		}
+		Ok(None)
+	}
+
+	/// "http://[user:pass@]host:port" or "socks5://[user:pass@]host:port", scheme http if not given.
+	fn parse(url: &str, no_proxy: Vec<String>) -> Result<Self, Error> {
+		let (kind, rest) = match url.split_once("://") {
+			Some(("http", rest)) => (ProxyKind::Http, rest),
+			Some(("socks5", rest)) => (ProxyKind::Socks5, rest),
+			Some(("socks5h", rest)) => (ProxyKind::Socks5h, rest),
+			Some((scheme, _)) => return Err(err(&format!("proxy scheme {} not supported", scheme))),
+			None => (ProxyKind::Http, url)
+		};
+		let rest = rest.trim_end_matches('/');
+		let (credentials, addr) = match rest.rsplit_once('@') {
+			Some((cred, addr)) => match cred.split_once(':') {
+				Some((user, pass)) => (Some((user.to_string(), pass.to_string())), addr),
+				None => (Some((cred.to_string(), String::new())), addr)
+			},
+			None => (None, rest)
+		};
+		let (host, port) = match addr.rsplit_once(':') {
+			Some((host, port)) => (host, port.parse().map_err(|_| err("invalid proxy port"))?),
+			None => (addr, if kind == ProxyKind::Http {8080} else {1080})
+		};
+		let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+		Ok(Self {kind, host, port, credentials, no_proxy})
+	}
+
+	/// Host matches NO_PROXY entry: exact, domain suffix or "*".
+	fn is_bypassed(&self, host: &str) -> bool {
+		self.no_proxy.iter().any(|np| {
+			let np = np.trim_start_matches('.');
+			np == "*" || host == np || host.ends_with(&format!(".{}", np))
+		})
+	}
+
+	/// Tunnel to host:port through proxy, direct connect if host bypassed.
+	/// Handshake timeouts left on stream, caller sets own.
+	pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+		if self.is_bypassed(host) {
+			return connect_tcp(host, port);
+		}
+		let mut tcp = connect_tcp(&self.host, self.port)?;
+		tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+		tcp.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+		match self.kind {
+			ProxyKind::Http => self.http_connect(&mut tcp, host, port)?,
+			ProxyKind::Socks5 | ProxyKind::Socks5h => self.socks5_connect(&mut tcp, host, port)?
+		}
+		Ok(tcp)
+	}
+
+	fn http_connect(&self, tcp: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+		let target = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+		let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+		if let Some((user, pass)) = &self.credentials {
+			req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", BASE64.encode(format!("{}:{}", user, pass))));
+		}
+		req.push_str("\r\n");
+		tcp.write_all(req.as_bytes())?;
+		// head read byte by byte, tunnel data must stay in socket
+		let mut head = Vec::new();
+		let mut byte = [0;1];
+		while !head.ends_with(b"\r\n\r\n") {
+			if tcp.read(&mut byte)? == 0 {
+				return Err(Error::new(ErrorKind::UnexpectedEof, "proxy closed connection"));
+			}
+			head.push(byte[0]);
+			if head.len() > MAX_HEAD_SIZE {
+				return Err(err("proxy answer too long"));
+			}
+		}
+		let status = String::from_utf8_lossy(&head);
+		let status = status.lines().next().unwrap_or_default();
+		match status.split_whitespace().nth(1) {
+			Some("200") => Ok(()),
+			_ => Err(err(&format!("proxy refused: {}", status)))
+		}
+	}
+
+	fn socks5_connect(&self, tcp: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+		let method = if self.credentials.is_some() { SOCKS_USER_PASS } else { SOCKS_NO_AUTH };
+		tcp.write_all(&[SOCKS_VERSION, 1, method])?;
+		let mut answ = [0;2];
+		tcp.read_exact(&mut answ)?;
+		if answ[0] != SOCKS_VERSION || answ[1] != method {
+			return Err(err("socks proxy refused auth method"));
+		}
+		if let Some((user, pass)) = &self.credentials {
+			if user.len() > 255 || pass.len() > 255 {
+				return Err(err("socks credentials too long"));
+			}
+			let mut auth = vec![1, user.len() as u8];
+			auth.extend_from_slice(user.as_bytes());
+			auth.push(pass.len() as u8);
+			auth.extend_from_slice(pass.as_bytes());
+			tcp.write_all(&auth)?;
+			tcp.read_exact(&mut answ)?;
+			if answ[1] != 0 {
+				return Err(err("socks proxy rejected credentials"));
+			}
+		}
+		let mut req = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0];
+		if self.kind == ProxyKind::Socks5h {
+			if host.len() > 255 {
+				return Err(err("host name too long"));
+			}
+			req.extend_from_slice(&[SOCKS_ATYP_DOMAIN, host.len() as u8]);
+			req.extend_from_slice(host.as_bytes());
+		} else {
+			match (host, port).to_socket_addrs()?.next().map(|addr| addr.ip()) {
+				Some(IpAddr::V4(ip)) => {
+					req.push(SOCKS_ATYP_IPV4);
+					req.extend_from_slice(&ip.octets());
+				},
+				Some(IpAddr::V6(ip)) => {
+					req.push(SOCKS_ATYP_IPV6);
+					req.extend_from_slice(&ip.octets());
+				},
+				None => return Err(err("host not resolved"))
+			}
+		}
+		req.extend_from_slice(&port.to_be_bytes());
+		tcp.write_all(&req)?;
+		let mut head = [0;4];
+		tcp.read_exact(&mut head)?;
+		if head[1] != 0 {
+			return Err(err(&format!("socks proxy refused connect, code {}", head[1])));
+		}
+		// bound address not needed, but must be read out
+		let addr_len = match head[3] {
+			SOCKS_ATYP_IPV4 => 4,
+			SOCKS_ATYP_IPV6 => 16,
+			SOCKS_ATYP_DOMAIN => {
+				let mut len = [0;1];
+				tcp.read_exact(&mut len)?;
+				len[0] as usize
+			},
+			_ => return Err(err("socks proxy answered unknown address type"))
+		};
+		let mut bound = vec![0;addr_len + 2];
+		tcp.read_exact(&mut bound)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	fn parse(url: &str) -> Proxy {
+		Proxy::parse(url, Vec::new()).unwrap()
+	}
+
+	fn from_env(policy: &ProxyPolicy, vars: &[(&str, &str)]) -> Option<Proxy> {
+		let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+		Proxy::from_vars(policy, |name| vars.get(name).cloned()).unwrap()
+	}
+
+	fn env_policy() -> ProxyPolicy {
+		ProxyPolicy {use_env: true, ..Default::default()}
+	}
+
+	#[test]
+	fn http_url() {
+		let p = parse("http://proxy.local:3128/");
+		assert!(p.kind == ProxyKind::Http);
+		assert_eq!((p.host.as_str(), p.port), ("proxy.local", 3128));
+		assert!(p.credentials.is_none());
+	}
+
+	#[test]
+	fn scheme_and_port_defaults() {
+		let p = parse("proxy.local");
+		assert!(p.kind == ProxyKind::Http);
+		assert_eq!(p.port, 8080);
+		assert_eq!(parse("socks5://proxy.local").port, 1080);
+	}
+
+	#[test]
+	fn socks_kinds() {
+		assert!(parse("socks5://h:1").kind == ProxyKind::Socks5);
+		assert!(parse("socks5h://h:1").kind == ProxyKind::Socks5h);
+	}
+
+	#[test]
+	fn credentials() {
+		let p = parse("socks5://user:p@ss@h:1080");
+		assert_eq!(p.credentials, Some((String::from("user"), String::from("p@ss"))));
+		assert_eq!(p.host, "h");
+		assert_eq!(parse("http://user@h:1").credentials, Some((String::from("user"), String::new())));
+	}
+
+	#[test]
+	fn ipv6_host() {
+		let p = parse("http://[::1]:3128");
+		assert_eq!((p.host.as_str(), p.port), ("::1", 3128));
+	}
+
+	#[test]
+	fn invalid_urls_rejected() {
+		assert!(Proxy::parse("https://h:1", Vec::new()).is_err());
+		assert!(Proxy::parse("socks4://h:1", Vec::new()).is_err());
+		assert!(Proxy::parse("http://h:port", Vec::new()).is_err());
+	}
+
+	#[test]
+	fn no_proxy_match() {
+		let p = Proxy::parse("http://h:1", vec![String::from(".example.com"), String::from("10.0.0.1")]).unwrap();
+		assert!(p.is_bypassed("example.com"));
+		assert!(p.is_bypassed("srv.example.com"));
+		assert!(p.is_bypassed("10.0.0.1"));
+		assert!(!p.is_bypassed("badexample.com"));
+		assert!(!p.is_bypassed("10.0.0.10"));
+		assert!(Proxy::parse("http://h:1", vec![String::from("*")]).unwrap().is_bypassed("any"));
+	}
+
+	#[test]
+	fn env_ignored_unless_allowed() {
+		assert!(from_env(&ProxyPolicy::default(), &[("HTTPS_PROXY", "http://h:1")]).is_none());
+		assert!(from_env(&env_policy(), &[]).is_none());
+	}
+
+	#[test]
+	fn policy_url_before_env() {
+		let policy = ProxyPolicy {url: Some(String::from("socks5h://p:2")), ..env_policy()};
+		let p = from_env(&policy, &[("HTTPS_PROXY", "http://h:1")]).unwrap();
+		assert_eq!((p.host.as_str(), p.port), ("p", 2));
+	}
+
+	#[test]
+	fn env_order_and_unsupported_skipped() {
+		let p = from_env(&env_policy(), &[("ALL_PROXY", "socks4://a:1"), ("https_proxy", "https://b:2"), ("HTTP_PROXY", "http://c:3")]).unwrap();
+		assert_eq!((p.host.as_str(), p.port), ("c", 3));
+		let p = from_env(&env_policy(), &[("ALL_PROXY", "socks5://a:1"), ("HTTP_PROXY", "http://c:3")]).unwrap();
+		assert_eq!(p.host, "a");
+		assert!(from_env(&env_policy(), &[("HTTPS_PROXY", "https://b:2")]).is_none());
+	}
+
+	#[test]
+	fn env_no_proxy_joined_with_policy() {
+		let policy = ProxyPolicy {no_proxy: vec![String::from("a.com")], ..env_policy()};
+		let p = from_env(&policy, &[("HTTP_PROXY", "http://c:3"), ("NO_PROXY", "b.com, ,c.com")]).unwrap();
+		assert_eq!(p.no_proxy, vec![String::from("a.com"), String::from("b.com"), String::from("c.com")]);
+	}
+
+	#[test]
+	fn invalid_policy_url_is_error() {
+		let policy = ProxyPolicy {url: Some(String::from("ftp://h:1")), ..Default::default()};
+		assert!(Proxy::from_policy(&policy).is_err());
+	}
+}
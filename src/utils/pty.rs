@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::Error;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+
+pub struct Pty {
+	pub master: File,
+	pub child: Child
+}
+
+impl Pty {
+	/// Hang up whole session of the child and reap it.
+	pub fn terminate(&mut self) {
+		unsafe {
+			libc::kill(-(self.child.id() as libc::pid_t), libc::SIGHUP);
+		}
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+/// Open new pseudo terminal and spawn cmd in it as session leader.
+pub fn spawn(cmd: &str, args: &[String]) -> Result<Pty, Error> {
+	let mut master_fd: libc::c_int = -1;
+	let mut slave_fd: libc::c_int = -1;
+	let res = unsafe {
+		libc::openpty(&mut master_fd, &mut slave_fd, std::ptr::null_mut(), std::ptr::null(), std::ptr::null())
+	};
+	if res != 0 {
+		return Err(Error::last_os_error());
+	}
+	let master = unsafe { File::from_raw_fd(master_fd) };
+	let slave = unsafe { File::from_raw_fd(slave_fd) };
+	set_cloexec(master_fd)?;
+	set_cloexec(slave_fd)?;
+
+	let mut command = Command::new(cmd);
+	command.args(args);
+	command.env("TERM", "xterm-256color");
+	command.stdin(Stdio::from(slave.try_clone()?));
+	command.stdout(Stdio::from(slave.try_clone()?));
+	command.stderr(Stdio::from(slave));
+	unsafe {
+		command.pre_exec(|| {
+			if libc::setsid() < 0 {
+				return Err(Error::last_os_error());
+			}
+			if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+				return Err(Error::last_os_error());
+			}
+			Ok(())
+		});
+	}
+	let child = command.spawn()?;
+	Ok(Pty {master, child})
+}
+
+fn set_cloexec(fd: libc::c_int) -> Result<(), Error> {
+	unsafe {
+		let flags = libc::fcntl(fd, libc::F_GETFD);
+		if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+			return Err(Error::last_os_error());
+		}
+	}
+	Ok(())
+}
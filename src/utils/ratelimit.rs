@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket, caps total rate of all users sharing it.
+/// Received data taken after the fact (take), bucket goes into debt and caller sleeps it off.
+/// Items over rate dropped (try_take), taken only while tokens left.
+pub struct RateLimiter {
+	rate: f64,				// tokens/s
+	burst: f64,
+	tokens: f64,
+	tl_refill: Instant
+}
+
+impl RateLimiter {
+	/// Bucket with one second burst.
+	pub fn new(rate: u64) -> Self {
+		Self::with_burst(rate as f64, rate as f64)
+	}
+
+	pub fn with_burst(rate: f64, burst: f64) -> Self {
+		Self {rate, burst, tokens: burst, tl_refill: Instant::now()}
+	}
+
+	/// New rate and burst for bucket in use, tokens kept.
+	pub fn set_limits(&mut self, rate: f64, burst: f64) {
+		self.rate = rate;
+		self.burst = burst;
+	}
+
+	fn refill(&mut self) {
+		self.tokens = (self.tokens + self.tl_refill.elapsed().as_secs_f64() * self.rate).min(self.burst);
+		self.tl_refill = Instant::now();
+	}
+
+	/// Take len bytes, return delay before next read.
+	pub fn take(&mut self, len: usize) -> Duration {
+		self.refill();
+		self.tokens -= len as f64;
+		if self.tokens >= 0.0 {
+			Duration::ZERO
+		} else {
+			Duration::from_secs_f64(-self.tokens / self.rate)
+		}
+	}
+
+	/// Take one token if there is one.
+	pub fn try_take(&mut self) -> bool {
+		self.refill();
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+
+	// time passed between takes refills only a few bytes at these rates
+	const SLACK: f64 = 0.05;
+
+	fn assert_about(delay: Duration, secs: f64) {
+		let got = delay.as_secs_f64();
+		assert!(got <= secs && got >= secs - SLACK, "delay {} s, expected about {} s", got, secs);
+	}
+
+	#[test]
+	fn burst_free() {
+		let mut rl = RateLimiter::new(1000);
+		assert_eq!(rl.take(1000), Duration::ZERO);
+	}
+
+	#[test]
+	fn debt_grows_with_takes() {
+		let mut rl = RateLimiter::new(1000);
+		assert_about(rl.take(1500), 0.5);
+		assert_about(rl.take(500), 1.0);
+	}
+
+	#[test]
+	fn debt_shared_by_concurrent_takers() {
+		let rl = Arc::new(Mutex::new(RateLimiter::new(1000)));
+		let handles: Vec<_> = (0..4).map(|_| {
+			let rl = rl.clone();
+			thread::spawn(move || rl.lock().unwrap().take(1000))
+		}).collect();
+		let mut delays: Vec<f64> = handles.into_iter().map(|h| h.join().unwrap().as_secs_f64()).collect();
+		delays.sort_by(|a, b| a.partial_cmp(b).unwrap());
+		// whoever comes later waits for debt of all before
+		for (i, delay) in delays.into_iter().enumerate() {
+			assert_about(Duration::from_secs_f64(delay), i as f64);
+		}
+	}
+
+	#[test]
+	fn try_take_stops_at_burst() {
+		let mut rl = RateLimiter::with_burst(1.0, 3.0);
+		assert!((0..3).all(|_| rl.try_take()));
+		assert!(!rl.try_take());
+	}
+
+	#[test]
+	fn try_take_refilled_by_time() {
+		let mut rl = RateLimiter::with_burst(10.0, 1.0);
+		assert!(rl.try_take());
+		assert!(!rl.try_take());
+		thread::sleep(Duration::from_millis(150));
+		assert!(rl.try_take());
+	}
+
+	#[test]
+	fn debt_paid_off_by_time() {
+		let mut rl = RateLimiter::new(10000);
+		rl.take(15000);
+		thread::sleep(Duration::from_millis(600));
+		assert_eq!(rl.take(0), Duration::ZERO);
+	}
+}
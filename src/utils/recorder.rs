@@ -0,0 +1,153 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use chrono::Utc;
+
+use crate::data_types::stream_api::{RecordHeader, RecordFrame, RecordDirection};
+use super::rmp_encode;
+use super::frame::write_frame;
+
+const REC_EXT: &str = "rec";
+const ACTIVE_EXT: &str = "rec.part";
+
+pub type SharedRecorder = Arc<Mutex<Recorder>>;
+
+/// Session recording, frames written while size limit not reached.
+/// File has ACTIVE_EXT until recorder dropped, so only finished recordings are listed.
+pub struct Recorder {
+	file: Option<BufWriter<File>>,
+	path: PathBuf,
+	started: Instant,
+	written: u64,
+	max_size: u64
+}
+
+impl Recorder {
+	pub fn create(dir: &str, header: RecordHeader, max_size: u64) -> Result<SharedRecorder, Error> {
+		fs::create_dir_all(dir)?;
+		let name = format!("{}_{}_{}", header.started, header.kind, header.stream_id);
+		let path = Path::new(dir).join(format!("{}.{}", name, ACTIVE_EXT));
+		let mut file = BufWriter::new(File::create(&path)?);
+		let raw = rmp_encode(&header)?;
+		write_frame(&mut file, &raw)?;
+		Ok(Arc::new(Mutex::new(Self {
+			file: Some(file),
+			path,
+			started: Instant::now(),
+			written: raw.len() as u64,
+			max_size
+		})))
+	}
+
+	pub fn record(&mut self, dir: RecordDirection, data: &[u8]) {
+		let file = match &mut self.file {
+			Some(file) => file,
+			None => return
+		};
+		let (dir, data) = if self.written + data.len() as u64 > self.max_size {
+			(RecordDirection::Truncated, &[][..])
+		} else {
+			(dir, data)
+		};
+		let truncated = matches!(dir, RecordDirection::Truncated);
+		let frame = RecordFrame {time: self.started.elapsed().as_millis() as u64, dir, data: data.to_vec()};
+		let res = rmp_encode(&frame).and_then(|raw| {
+			write_frame(file, &raw)?;
+			Ok(raw.len() as u64)
+		});
+		match res {
+			Ok(len) => self.written += len,
+			Err(e) => {
+				println!("[RECORDER] fail to write {:?}: {:?}", self.path, e);
+				self.file = None;
+			}
+		}
+		if truncated {
+			self.finish();
+		}
+	}
+
+	fn finish(&mut self) {
+		if let Some(mut file) = self.file.take() {
+			let _ = file.flush();
+		}
+	}
+}
+
+impl Drop for Recorder {
+	fn drop(&mut self) {
+		self.finish();
+		let _ = fs::rename(&self.path, self.path.with_extension("").with_extension(REC_EXT));
+	}
+}
+
+pub fn new_header(stream_id: i32, program_id: Option<i32>, kind: &str) -> RecordHeader {
+	RecordHeader {
+		stream_id,
+		program_id,
+		kind: String::from(kind),
+		started: Utc::now().timestamp_millis()
+	}
+}
+
+/// Finished recordings with modification time, oldest first.
+pub fn list(dir: &str) -> Vec<(PathBuf, SystemTime, u64)> {
+	let mut files = Vec::new();
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(_) => return files
+	};
+	for entry in entries.flatten() {
+		let path = entry.path();
+		if path.extension().and_then(|e| e.to_str()) != Some(REC_EXT) {
+			continue;
+		}
+		if let Ok(meta) = entry.metadata() {
+			if meta.is_file() {
+				files.push((path, meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta.len()));
+			}
+		}
+	}
+	files.sort_by_key(|f| f.1);
+	files
+}
+
+/// Recordings left active by crash or kill finished as they are, last frame may be torn.
+/// Called before any session started, so every active file is stale.
+pub fn recover(dir: &str) {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(_) => return
+	};
+	for entry in entries.flatten() {
+		let path = entry.path();
+		if !path.to_string_lossy().ends_with(&format!(".{}", ACTIVE_EXT)) {
+			continue;
+		}
+		match fs::rename(&path, path.with_extension("").with_extension(REC_EXT)) {
+			Ok(()) => println!("[RECORDER] recover stale recording {:?}", path),
+			Err(e) => println!("[RECORDER] fail to recover {:?}: {:?}", path, e)
+		}
+	}
+}
+
+/// Remove recordings older than retention, then oldest ones while total size above limit.
+pub fn cleanup(dir: &str, max_total_size: u64, retention: Duration) {
+	let files = list(dir);
+	let mut total: u64 = files.iter().map(|f| f.2).sum();
+	for (path, modified, size) in files {
+		let expired = modified.elapsed().map(|age| age > retention).unwrap_or(false);
+		if !expired && total <= max_total_size {
+			continue;
+		}
+		match fs::remove_file(&path) {
+			Ok(()) => {
+				println!("[RECORDER] remove recording {:?}", path);
+				total -= size;
+			},
+			Err(e) => println!("[RECORDER] fail to remove {:?}: {:?}", path, e)
+		}
+	}
+}
@@ -0,0 +1,353 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::data_types::stream_api::RecordDirection;
+use super::recorder::SharedRecorder;
+use super::tls::{ServerStream, TlsStream};
+
+const BUFSIZE: usize = 16384;
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+const ZSTD_LEVEL: i32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(20);
+const TLS_POLL_TIMEOUT: Duration = Duration::from_millis(10);
+const TLS_CHANNEL_CAP: usize = 16;				// chunks between pumps and TLS io thread
+
+struct Shared {
+	stop: AtomicBool,
+	bytes_in: AtomicUsize,
+	bytes_out: AtomicUsize,
+	tl_io: Mutex<Instant>,
+	reason: Mutex<Option<&'static str>>
+}
+
+impl Shared {
+	fn finish(&self, reason: &'static str) {
+		let mut r = self.reason.lock().unwrap();
+		if r.is_none() {
+			*r = Some(reason);
+		}
+	}
+}
+
+/// Bidirectional pump between server stream and local end, each direction in own thread.
+/// Io is blocking on both sides, so slow side holds back the fast one instead of dropping data.
+/// TLS session owned by one io thread, pumps pass chunks to it over bounded channels.
+/// Every relayed chunk written to recorder if given.
+/// If compressed, server side is zstd stream, flushed after every chunk and finished on shutdown.
+pub struct Relay {
+	shared: Arc<Shared>,
+	tcp: Arc<Mutex<Option<ServerStream>>>,	// None while connecting
+	started: Instant
+}
+
+impl Relay {
+	fn new() -> Self {
+		Self {
+			shared: Arc::new(Shared {
+				stop: AtomicBool::new(false),
+				bytes_in: AtomicUsize::new(0),
+				bytes_out: AtomicUsize::new(0),
+				tl_io: Mutex::new(Instant::now()),
+				reason: Mutex::new(None)
+			}),
+			tcp: Arc::new(Mutex::new(None)),
+			started: Instant::now()
+		}
+	}
+
+	fn share(&self) -> Self {
+		Self {shared: self.shared.clone(), tcp: self.tcp.clone(), started: self.started}
+	}
+
+	pub fn spawn(
+		tcp: ServerStream,
+		reader: Box<dyn Read + Send>,
+		writer: Box<dyn Write + Send>,
+		rec: Option<SharedRecorder>,
+		compressed: bool
+	) -> Result<Self, Error> {
+		let relay = Self::new();
+		relay.start(tcp, reader, writer, rec, compressed)?;
+		Ok(relay)
+	}
+
+	/// Connect to server in own thread, then relay, so caller not held by connect and handshakes.
+	/// Relay finishes with "connect failed" if connect or start fails, connect reports its error itself.
+	pub fn connect<C>(
+		connect: C,
+		reader: Box<dyn Read + Send>,
+		writer: Box<dyn Write + Send>,
+		rec: Option<SharedRecorder>,
+		compressed: bool
+	) -> Self
+	where C: FnOnce() -> Result<ServerStream, Error> + Send + 'static {
+		let relay = Self::new();
+		let r = relay.share();
+		thread::spawn(move || {
+			if connect().and_then(|tcp| r.start(tcp, reader, writer, rec, compressed)).is_err() {
+				r.shared.finish("connect failed");
+			}
+		});
+		relay
+	}
+
+	fn start(
+		&self,
+		tcp: ServerStream,
+		mut reader: Box<dyn Read + Send>,
+		mut writer: Box<dyn Write + Send>,
+		rec: Option<SharedRecorder>,
+		compressed: bool
+	) -> Result<(), Error> {
+		tcp.set_nodelay(true)?;
+		// connect timeouts dropped, server may hold back relay as long as it needs
+		tcp.set_read_timeout(None)?;
+		tcp.set_write_timeout(None)?;
+		*self.tcp.lock().unwrap() = Some(tcp.try_clone()?);
+		if self.shared.stop.load(Ordering::Relaxed) {
+			// closed while connecting
+			let _ = tcp.shutdown(Shutdown::Both);
+			return Ok(());
+		}
+		let shared = self.shared.clone();
+
+		let (server_up, server_down): (Box<dyn Write + Send>, Box<dyn Read + Send>) = match &tcp {
+			ServerStream::Tls(session, _) => {
+				let (up, down) = tls_io(session.clone(), tcp.try_clone()?, shared.clone())?;
+				(Box::new(up), Box::new(down))
+			},
+			ServerStream::Plain(_) => (Box::new(tcp.try_clone()?), Box::new(tcp.try_clone()?))
+		};
+
+		let tcp_up = tcp.try_clone()?;
+		let mut up: Box<dyn Write + Send> = if compressed {
+			// finished on drop, error ignored as server may be gone already
+			Box::new(zstd::stream::write::Encoder::new(server_up, ZSTD_LEVEL)?.on_finish(|_| {}))
+		} else {
+			server_up
+		};
+		let sh = shared.clone();
+		let rec_up = rec.clone();
+		thread::spawn(move || {
+			let reason = pump(&mut reader, &mut up, &sh, &sh.bytes_out, (rec_up, RecordDirection::Out), ("local closed", "master disconnected"));
+			sh.finish(reason);
+			// end of zstd stream written before connection closed
+			drop(up);
+			// TLS io thread closes after queued chunks written
+			if !tcp_up.is_tls() {
+				let _ = tcp_up.shutdown(Shutdown::Both);
+			}
+		});
+
+		let tcp_down = tcp;
+		let mut down: Box<dyn Read + Send> = if compressed {
+			Box::new(zstd::stream::read::Decoder::new(server_down)?)
+		} else {
+			server_down
+		};
+		let sh = shared;
+		thread::spawn(move || {
+			let reason = pump(&mut down, &mut writer, &sh, &sh.bytes_in, (rec, RecordDirection::In), ("master disconnected", "local closed"));
+			sh.finish(reason);
+			let _ = tcp_down.shutdown(Shutdown::Both);
+		});
+
+		Ok(())
+	}
+
+	/// Reason of the first finished direction, None while both are alive.
+	pub fn close_reason(&self) -> Option<&'static str> {
+		*self.shared.reason.lock().unwrap()
+	}
+
+	/// Stop both directions, threads blocked on local end exit after it closed.
+	pub fn close(&self) {
+		self.shared.stop.store(true, Ordering::Relaxed);
+		self.shared.finish("closed");
+		if let Some(tcp) = &*self.tcp.lock().unwrap() {
+			let _ = tcp.shutdown(Shutdown::Both);
+		}
+	}
+
+	pub fn idle(&self) -> Duration {
+		self.shared.tl_io.lock().unwrap().elapsed()
+	}
+
+	pub fn descr(&self) -> String {
+		format!(
+			"duration {}s, in {} B, out {} B",
+			self.started.elapsed().as_secs(),
+			self.shared.bytes_in.load(Ordering::Relaxed),
+			self.shared.bytes_out.load(Ordering::Relaxed)
+		)
+	}
+}
+
+fn pump(
+	from: &mut dyn Read,
+	to: &mut dyn Write,
+	sh: &Shared,
+	counter: &AtomicUsize,
+	rec: (Option<SharedRecorder>, RecordDirection),
+	reasons: (&'static str, &'static str)		// from closed, to closed
+) -> &'static str {
+	let (from_closed, to_closed) = reasons;
+	let mut buf = vec![0;BUFSIZE];
+	loop {
+		if sh.stop.load(Ordering::Relaxed) {
+			return "closed";
+		}
+		let len = match from.read(&mut buf) {
+			Ok(0) => return from_closed,
+			Ok(len) => len,
+			Err(ref e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock) => continue,
+			// pty gives EIO after the last slave fd closed
+			Err(_) => return from_closed
+		};
+		if write_chunk(to, &buf[..len], sh).is_err() {
+			return to_closed;
+		}
+		if let (Some(r), dir) = &rec {
+			r.lock().unwrap().record(*dir, &buf[..len]);
+		}
+		counter.fetch_add(len, Ordering::Relaxed);
+		*sh.tl_io.lock().unwrap() = Instant::now();
+	}
+}
+
+/// Write whole chunk, local end not ready (nonblocking program stdin) retried until relay stopped,
+/// so program that not reads stdin can't hang relay.
+fn write_chunk(to: &mut dyn Write, data: &[u8], sh: &Shared) -> Result<(), Error> {
+	let mut pos = 0;
+	while pos < data.len() {
+		match to.write(&data[pos..]) {
+			Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "local end closed")),
+			Ok(len) => pos += len,
+			Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+				if sh.stop.load(Ordering::Relaxed) {
+					return Err(e);
+				}
+				thread::sleep(WRITE_RETRY_DELAY);
+			},
+			Err(e) => return Err(e)
+		}
+	}
+	to.flush()
+}
+
+/// Thread owning TLS session of relay, so reading server never holds back writing to it.
+/// Queued chunks written first, then server read with short timeout.
+/// Returns writer and reader for pumps, reader ends when server closed.
+fn tls_io(session: Arc<Mutex<TlsStream>>, tcp: ServerStream, sh: Arc<Shared>) -> Result<(ChannelWriter, ChannelReader), Error> {
+	tcp.set_read_timeout(Some(TLS_POLL_TIMEOUT))?;
+	let (up_tx, up_rx) = mpsc::sync_channel::<Vec<u8>>(TLS_CHANNEL_CAP);
+	let (down_tx, down_rx) = mpsc::sync_channel::<Vec<u8>>(TLS_CHANNEL_CAP);
+	thread::spawn(move || {
+		// relay is the only user of session, lock held for whole life
+		let mut tls = session.lock().unwrap();
+		let mut buf = vec![0;BUFSIZE];
+		'io: while !sh.stop.load(Ordering::Relaxed) {
+			loop {
+				match up_rx.try_recv() {
+					Ok(data) => if tls.write_all(&data).and_then(|_| tls.flush()).is_err() {
+						break 'io;
+					},
+					Err(TryRecvError::Empty) => break,
+					Err(TryRecvError::Disconnected) => {
+						tls.conn.send_close_notify();
+						let _ = tls.flush();
+						break 'io;
+					}
+				}
+			}
+			match tls.read(&mut buf) {
+				Ok(0) => break,
+				Ok(len) => if down_tx.send(buf[..len].to_vec()).is_err() {
+					break;
+				},
+				Err(ref e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock) => (),
+				Err(_) => break
+			}
+		}
+		let _ = tcp.shutdown(Shutdown::Both);
+	});
+	Ok((ChannelWriter(up_tx), ChannelReader::new(Arc::new(Mutex::new(down_rx)))))
+}
+
+/// Write adapter over bounded channel of chunks, blocks while channel full.
+struct ChannelWriter(SyncSender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+		self.0.send(buf.to_vec()).map_err(|_| Error::new(ErrorKind::BrokenPipe, "server closed"))?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		Ok(())
+	}
+}
+
+/// Set O_NONBLOCK on fd, for local ends written by relay.
+pub fn set_nonblocking<F: AsRawFd>(f: &F) -> Result<(), Error> {
+	let fd = f.as_raw_fd();
+	unsafe {
+		let flags = libc::fcntl(fd, libc::F_GETFL);
+		if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+			return Err(Error::last_os_error());
+		}
+	}
+	Ok(())
+}
+
+/// Read adapter over channel of chunks (program stdout), wakes up periodically
+/// with TimedOut so relay can notice stop.
+pub struct ChannelReader {
+	rx: Arc<Mutex<Receiver<Vec<u8>>>>,
+	pending: Vec<u8>,
+	pos: usize
+}
+
+impl ChannelReader {
+	pub fn new(rx: Arc<Mutex<Receiver<Vec<u8>>>>) -> Self {
+		Self {rx, pending: Vec::new(), pos: 0}
+	}
+}
+
+impl Read for ChannelReader {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		if self.pos >= self.pending.len() {
+			let data = match self.rx.lock().unwrap().recv_timeout(RECV_TIMEOUT) {
+				Ok(data) => data,
+				Err(RecvTimeoutError::Timeout) => return Err(Error::new(ErrorKind::TimedOut, "no data")),
+				Err(RecvTimeoutError::Disconnected) => return Ok(0)
+			};
+			self.pending = data;
+			self.pos = 0;
+		}
+		let len = buf.len().min(self.pending.len() - self.pos);
+		buf[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+		self.pos += len;
+		Ok(len)
+	}
+}
+
+/// Write adapter for writer shared with its owner (program stdin), lock held only for one write,
+/// writer must be nonblocking.
+pub struct SharedWriter<W: Write>(pub Arc<Mutex<W>>);
+
+impl<W: Write> Write for SharedWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+		self.0.lock().unwrap().write(buf)
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		self.0.lock().unwrap().flush()
+	}
+}
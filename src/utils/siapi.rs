@@ -1,11 +1,22 @@
-use std::net::TcpStream;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 use pbr::ProgressBar;
 use rmp_serde as rmps;
-use std::io::{Error, Write, Read};
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Write, Read, Seek, SeekFrom};
+use std::thread;
+use ring::digest::{Context, SHA256};
 
-use crate::data_types;
-use crate::utils::{err, rmp_decode};
+use crate::data_types::{self, ServerAddr};
+use crate::utils::{err, rmp_decode, compress};
+use crate::utils::tls::{ServerStream, TlsConnector};
+use crate::utils::framed::Framed;
+use crate::utils::sign;
+use crate::utils::ratelimit::RateLimiter;
+use crate::utils::proxy::Proxy;
 use data_types::data_server::*;
 use data_types::file_server;
 use crate::utils::mos;
@@ -13,163 +24,526 @@ use data_types::data_server::GetPointConfigAnsw as PointConfig;
 use data_types::data_server::GetProgramConfigAnsw as ProgramConfig;
 
 const FILE_BUF_SIZE: usize = 4096;
+const DOWNLOAD_ATTEMPTS: u32 = 5;                   // in a row without progress
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
 const FIRST_DATA_DELAY: Duration = Duration::from_millis(1000);
 const READ_TIMEOUT: Duration = Duration::from_millis(5000);
 const WRITE_TIMEOUT: Duration = Duration::from_millis(5000);
+/// Supported by this build, policies decide which are used.
+const FEATURES: &[&str] = &[
+    FEATURE_BATCH, FEATURE_COMPRESS, FEATURE_FRAMED, FEATURE_SIGNED, FEATURE_TOKEN_ROTATION, FEATURE_RESUME,
+    FEATURE_OFFLINE_REPORT, FEATURE_SHELL, FEATURE_TRANSFER, FEATURE_TUNNEL, FEATURE_RECORDING, FEATURE_DELTA
+];
 
 #[derive(Clone)]
 pub struct IntApi {
-    host: String,
-	data_port: u16,
-	file_port: u16,
-    auth: Auth
+    servers: Arc<Vec<ServerAddr>>,          // main first, then fallbacks
+    current: Arc<AtomicUsize>,              // shared with clones, switched on failover
+    auth: Arc<Mutex<Auth>>,                 // shared with clones, token may be rotated
+    signing: bool,
+    compress_threshold: Option<usize>,      // None - data port messages not framed
+    peer_compress: Arc<AtomicBool>,         // server answered framed, so accepts compressed
+    tls: Option<Arc<TlsConnector>>,
+    framed: Option<Arc<Mutex<Framed>>>,     // None - connection per request
+    download_rate: Option<Arc<Mutex<RateLimiter>>>,
+    proxy: Option<Arc<Proxy>>,
+    peer: Arc<Mutex<Option<Capabilities>>>, // of current server, None - Hello not done yet
+    refused: Option<Arc<String>>            // reason all connections refused
 }
 
 impl IntApi {
-	pub fn new(host: String, data_port: u16, file_port: u16, id: i32, token: Vec<u8>) -> Self {
+	pub fn new(server: ServerAddr, id: i32, token: Vec<u8>) -> Self {
         Self {
-            host: host,
-            data_port: data_port,
-            file_port: file_port,
-            auth: Auth {id: id, token: token}
+            servers: Arc::new(vec![server]),
+            current: Arc::new(AtomicUsize::new(0)),
+            auth: Arc::new(Mutex::new(Auth {id, token})),
+            signing: false,
+            compress_threshold: None,
+            peer_compress: Arc::new(AtomicBool::new(false)),
+            tls: None,
+            framed: None,
+            download_rate: None,
+            proxy: None,
+            peer: Arc::new(Mutex::new(None)),
+            refused: None
         }
     }
 
+    /// Make all server connections through proxy.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(Arc::new(proxy));
+        self
+    }
+
+    /// Cap total rate of file downloads, bytes/s.
+    pub fn with_download_rate(mut self, rate: u64) -> Self {
+        self.download_rate = Some(Arc::new(Mutex::new(RateLimiter::new(rate))));
+        self
+    }
+
+    /// Sign requests instead of sending token.
+    pub fn with_signing(mut self) -> Self {
+        self.signing = true;
+        self
+    }
+
+    /// Use rotated token for next requests, in all clones.
+    pub fn set_token(&self, token: Vec<u8>) {
+        self.auth.lock().unwrap().token = token;
+    }
+
+    /// Use new credentials after registered again, in all clones.
+    pub fn set_auth(&self, auth: Auth) {
+        *self.auth.lock().unwrap() = auth;
+    }
+
+    /// Auth for request, token left empty if request signed.
+    fn auth(&self) -> Auth {
+        let auth = self.auth.lock().unwrap();
+        Auth {id: auth.id, token: if self.signing { Vec::new() } else { auth.token.clone() }}
+    }
+
+    /// Keep one framed connection to data port for all requests.
+    pub fn with_framing(mut self, idle_timeout: Duration) -> Self {
+        self.framed = Some(Arc::new(Mutex::new(Framed::new(idle_timeout))));
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TlsConnector) -> Self {
+        self.tls = Some(Arc::new(tls));
+        self.refused = None;
+        self
+    }
+
+    /// Refuse all connections, for TLS that can't be set up, so nothing goes in plain.
+    pub fn with_refused(mut self, reason: String) -> Self {
+        self.refused = Some(Arc::new(reason));
+        self
+    }
+
+    /// Servers tried in turn after main one on failover.
+    pub fn with_fallback(mut self, servers: Vec<ServerAddr>) -> Self {
+        let mut all = (*self.servers).clone();
+        all.extend(servers);
+        self.servers = Arc::new(all);
+        self
+    }
+
+    /// Switch all clones to next server, false if there is no other one.
+    pub fn failover(&self) -> bool {
+        if self.servers.len() < 2 {
+            return false;
+        }
+        self.switch((self.current.load(Ordering::Relaxed) + 1) % self.servers.len());
+        true
+    }
+
+    /// Current server is one of fallbacks.
+    pub fn on_fallback(&self) -> bool {
+        !self.current.load(Ordering::Relaxed).is_multiple_of(self.servers.len())
+    }
+
+    /// Hello to main server aside of current connection, switch back to main if it answered.
+    pub fn reprobe_main(&self) -> bool {
+        let probe = Self {
+            servers: Arc::new(vec![self.servers[0].clone()]),
+            current: Arc::new(AtomicUsize::new(0)),
+            peer_compress: Arc::new(AtomicBool::new(false)),
+            framed: None,
+            peer: Arc::new(Mutex::new(None)),
+            ..self.clone()
+        };
+        match probe.hello() {
+            Ok(_) => {
+                self.switch(0);
+                true
+            },
+            Err(e) => {
+                println!("[SIAPI] main server {} still unreachable: {:?}", self.servers[0].host, e);
+                false
+            }
+        }
+    }
+
+    fn switch(&self, idx: usize) {
+        self.current.store(idx, Ordering::Relaxed);
+        if let Some(framed) = &self.framed {
+            framed.lock().unwrap().reset();
+        }
+        self.reset_peer();
+        println!("[SIAPI] switch to server {}", self.servers[idx].host);
+    }
+
+    fn addr(&self) -> &ServerAddr {
+        &self.servers[self.current.load(Ordering::Relaxed) % self.servers.len()]
+    }
+
+    pub fn stream_port(&self) -> u16 {
+        self.addr().stream_port
+    }
+
+    /// Connection to port of current server, TLS if enabled.
+    pub fn connect(&self, port: u16) -> Result<ServerStream, Error> {
+        if let Some(reason) = &self.refused {
+            return Err(err(reason));
+        }
+        ServerStream::connect(&self.addr().host, port, self.tls.as_deref(), self.proxy.as_deref())
+    }
+
+    /// Offer compression of data port messages larger than threshold.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_threshold = Some(threshold);
+        self
+    }
+
+    /// Exchange capabilities with current server, so newer features used only if it has them.
+    /// Server that closes connection or answers garbage taken as legacy, timeout is error so hello asked again.
+    pub fn hello(&self) -> Result<Capabilities, Error> {
+        let local = Capabilities {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            revision: PROTOCOL_REVISION,
+            features: FEATURES.iter().map(|f| f.to_string()).collect()
+        };
+        let peer = match self.data_request(&Request::Hello(self.auth(), local)) {
+            Ok(answ_raw) => rmp_decode(&answ_raw).unwrap_or_else(|_| Capabilities::legacy()),
+            // old server closes connection on unknown request
+            Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset) => Capabilities::legacy(),
+            Err(e) => return Err(e)
+        };
+        println!("[SIAPI] server {} version '{}', protocol revision {}, features: {:?}", self.addr().host, peer.version, peer.revision, peer.features);
+        if self.signing && !peer.has(FEATURE_SIGNED) {
+            // not downgraded, token must not be sent where signing configured
+            println!("[SIAPI] server not advertises signed requests, they are sent anyway");
+        }
+        *self.peer.lock().unwrap() = Some(peer.clone());
+        Ok(peer)
+    }
+
+    /// Capabilities of current server, None until hello done.
+    pub fn peer(&self) -> Option<Capabilities> {
+        self.peer.lock().unwrap().clone()
+    }
+
+    /// Current server has feature, false until hello done.
+    pub fn peer_has(&self, feature: &str) -> bool {
+        self.peer.lock().unwrap().as_ref().map(|p| p.has(feature)).unwrap_or(false)
+    }
+
+    /// Forget capabilities, server may be replaced or upgraded while unreachable.
+    pub fn reset_peer(&self) {
+        *self.peer.lock().unwrap() = None;
+    }
+
     pub fn get_host(&self) -> String {
-        self.host.clone()
+        self.addr().host.clone()
     }
     
     pub fn send_report(&self, report: Report) -> Result<(), Error> {
-        let req = Request::AddReport(self.auth.clone(), report);
+        let req = Request::AddReport(self.auth(), report);
         let answ_raw = self.data_request(&req)?;
         let _: OkAnsw = rmp_decode(&answ_raw)?;
         Ok(())
     }
     
     pub fn send_stat(&self, stat: Stat) -> Result<(), Error> {
-        let req = Request::AddStat(self.auth.clone(), stat);
+        let req = Request::AddStat(self.auth(), stat);
         let answ_raw = self.data_request(&req)?;
         let _: OkAnsw = rmp_decode(&answ_raw)?;
         Ok(())
     }
 
     pub fn send_log(&self, log: Log) -> Result<(), Error> {
-        let req = Request::AddLog(self.auth.clone(), log);
+        let req = Request::AddLog(self.auth(), log);
+        let answ_raw = self.data_request(&req)?;
+        let _: OkAnsw = rmp_decode(&answ_raw)?;
+        Ok(())
+    }
+
+    /// Send items in one request, return ids of accepted items.
+    pub fn send_batch(&self, items: Vec<BatchItem>) -> Result<Vec<u64>, Error> {
+        let req = Request::AddBatch(self.auth(), items);
+        let answ_raw = self.data_request(&req)?;
+        let answ: AddBatchAnsw = rmp_decode(&answ_raw)?;
+        Ok(answ.0)
+    }
+
+    /// Tell server that current token is received, old one dropped by server after it.
+    pub fn confirm_token(&self) -> Result<(), Error> {
+        let req = Request::ConfirmToken(self.auth());
         let answ_raw = self.data_request(&req)?;
         let _: OkAnsw = rmp_decode(&answ_raw)?;
         Ok(())
     }
 
     pub fn send_status(&self, status: ProgramStatus) -> Result<(), Error> {
-        let req = Request::SetStatus(self.auth.clone(), status);
+        let req = Request::SetStatus(self.auth(), status);
         self.data_request(&req)?;
         Ok(())
     }
 
     pub fn send_run_status(&self, status: ProgramRunStatus) -> Result<(), Error> {
-        let req = Request::SetRunStatus(self.auth.clone(), status);
+        let req = Request::SetRunStatus(self.auth(), status);
         self.data_request(&req)?;
         Ok(())
     }
     
     pub fn poll(&self) -> Result<PollAnsw, Error> {
-        let req = Request::Poll(self.auth.clone());
+        let req = Request::Poll(self.auth());
         let answ_raw = self.data_request(&req)?;
         Ok(rmp_decode(&answ_raw)?)
     }
     
     pub fn get_update_data(&self, hashes: Vec<ProgramHashes>) -> Result<GetUpdateDataAnsw, Error> {
-        let req = Request::GetUpdateData(self.auth.clone(), hashes);
+        let req = Request::GetUpdateData(self.auth(), hashes);
         let answ_raw = self.data_request(&req)?;
         Ok(rmp_decode(&answ_raw)?)
     }
     
     pub fn get_point_config(&self) -> Result<PointConfig, Error> {
-        let req = Request::GetPointConfig(self.auth.clone());
+        let req = Request::GetPointConfig(self.auth());
         let answ_raw = self.data_request(&req)?;
         Ok(rmp_decode(&answ_raw)?)
     }
     
     pub fn get_program_config(&self, config_id: i32) -> Result<ProgramConfig, Error> {
-        let req = Request::GetProgramConfig(self.auth.clone(), config_id);
+        let req = Request::GetProgramConfig(self.auth(), config_id);
         let answ_raw = self.data_request(&req)?;
         Ok(rmp_decode(&answ_raw)?)
     }
 
     pub fn download_asset(&self, program_id: i32) -> Result<(String, Vec<u8>), Error> {
-        let req = file_server::Request {
-            point_id: self.auth.id,
-            token: self.auth.token.clone(),
-            point_program_id: program_id,
-            res_type: file_server::ResourceType::Asset
-        };
+        let req = self.file_request(program_id, file_server::ResourceType::Asset);
         let fname = format!("{}_asset", program_id);
         let answ = self.download_file(req, &fname)?;
         Ok((fname, answ.hash))
     }
 
     pub fn download_program(&self, program_id: i32) -> Result<(String, Vec<u8>), Error> {
-        let req = file_server::Request {
-            point_id: self.auth.id,
-            token: self.auth.token.clone(),
-            point_program_id: program_id,
-            res_type: file_server::ResourceType::Build
-        };
+        let req = self.file_request(program_id, file_server::ResourceType::Build);
         let fname = format!("{}_build", program_id);
         let answ = self.download_file(req, &fname)?;
         Ok((fname, answ.hash))
     }
 
+    /// Build by patch against cached archive of installed build (base_hash), result must have hash.
+    /// Full build downloaded if there is no such archive, patch not downloaded or result mismatches.
+    pub fn download_program_patch(&self, program_id: i32, base_hash: Vec<u8>, hash: Vec<u8>) -> Result<(String, Vec<u8>), Error> {
+        let fname = format!("{}_build", program_id);
+        let patch_name = format!("{}_build_patch", program_id);
+        let res = match mos::is_patch_base(&fname, &base_hash) {
+            Ok(true) => {
+                let req = self.file_request(program_id, file_server::ResourceType::BuildPatch(base_hash));
+                self.download_file(req, &patch_name).and_then(|_| mos::apply_patch(&fname, &patch_name, &hash))
+            },
+            Ok(false) => Err(err("no cached archive of installed build or it is too big")),
+            Err(e) => Err(e)
+        };
+        let _ = mos::remove_temp_arch(&patch_name);
+        match res {
+            Ok(_) => Ok((fname, hash)),
+            Err(e) => {
+                println!("[SIAPI] patch for build of {} failed: {:?}, download full build", program_id, e);
+                self.download_program(program_id)
+            }
+        }
+    }
+
+    /// Upload recording file to file server, server answers with hash and size of stored file.
+    pub fn upload_recording(&self, path: &Path) -> Result<(), Error> {
+        let mut file = File::open(path)?;
+        let hash = mos::hash_file(&mut file)?;
+        let fsize = file.stream_position()?;
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => return Err(err("invalid recording path"))
+        };
+        let req = self.file_request(0, file_server::ResourceType::Recording(name, fsize));
+        let req_raw = self.encode_file_request(&req)?;
+        let mut stream = self.connect(self.addr().file_port)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        stream.write_all(&req_raw)?;
+        file.rewind()?;
+        std::io::copy(&mut file.take(fsize), &mut stream)?;
+        stream.finish_write()?;
+        let mut answ_raw = Vec::new();
+        stream.read_to_end(&mut answ_raw)?;
+        let answ: file_server::Answer = rmp_decode(&answ_raw)?;
+        if answ.hash != hash || answ.fsize as u64 != fsize {
+            return Err(err("integrity error"));
+        }
+        Ok(())
+    }
+
     pub fn register(&self, name: String, firm: Option<String>) -> Result<RegisterAnsw, Error> {
         let answ_raw = self.data_request(&Request::Register(name, firm))?;
         Ok(rmp_decode(&answ_raw)?)
     }
 
-    fn data_request(&self, req: &Request) -> Result<Vec<u8>, Error> {
-        let mut stream = TcpStream::connect(format!("{}:{}", &self.host, self.data_port))?;
+    fn file_request(&self, program_id: i32, res_type: file_server::ResourceType) -> file_server::Request {
+        let auth = self.auth();
+        file_server::Request {
+            point_id: auth.id,
+            token: auth.token,
+            point_program_id: program_id,
+            res_type
+        }
+    }
+
+    /// Request or Resume, wrapped in Signed if signing enabled.
+    fn encode_file_request<T: Serialize>(&self, req: &T) -> Result<Vec<u8>, Error> {
+        let body = rmps::encode::to_vec(req).unwrap();
+        if !self.signing {
+            return Ok(body);
+        }
+        let auth = self.auth.lock().unwrap().clone();
+        let signature = sign::sign(&auth, &body)?;
+        Ok(rmps::encode::to_vec(&file_server::ExtRequest::Signed(auth.id, signature, body)).unwrap())
+    }
+
+    fn data_connect(&self) -> Result<ServerStream, Error> {
+        let stream = self.connect(self.addr().data_port)?;
         stream.set_read_timeout(Some(READ_TIMEOUT))?;
         stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
-        let data = rmps::encode::to_vec(&req).unwrap();
-        stream.write_all(&data)?;
-        let mut buf = Vec::new();
-        stream.read_to_end(&mut buf)?;
-        Ok(buf)
+        Ok(stream)
+    }
+
+    fn data_request(&self, req: &Request) -> Result<Vec<u8>, Error> {
+        let mut data = rmps::encode::to_vec(&req).unwrap();
+        if self.signing && !matches!(req, Request::Register(_, _)) {
+            let auth = self.auth.lock().unwrap().clone();
+            let signature = sign::sign(&auth, &data)?;
+            data = rmps::encode::to_vec(&Request::Signed(auth.id, signature, data)).unwrap();
+        }
+        // legacy server fails on compress framed message
+        let compress_threshold = self.compress_threshold.filter(|_| self.peer_has(FEATURE_COMPRESS));
+        if let Some(threshold) = compress_threshold {
+            let compress = self.peer_compress.load(Ordering::Relaxed) && data.len() >= threshold;
+            data = compress::encode(&data, compress)?;
+        }
+        let framed_answ = match &self.framed {
+            Some(framed) if self.peer_has(FEATURE_FRAMED) => framed.lock().unwrap().request(|| self.data_connect(), &data)?,
+            _ => None
+        };
+        let buf = match framed_answ {
+            Some(buf) => buf,
+            None => {
+                let mut stream = self.data_connect()?;
+                stream.write_all(&data)?;
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf)?;
+                buf
+            }
+        };
+        if compress_threshold.is_none() {
+            return Ok(buf);
+        }
+        let (answ, framed) = compress::decode(&buf)?;
+        self.peer_compress.store(framed, Ordering::Relaxed);
+        Ok(answ)
     }
     
+    /// Download file into temp arch, failed attempt resumed from received part.
+    /// Attempts repeated with growing delay while they make progress.
     fn download_file(&self, req: file_server::Request, temp_file_name: &str) -> Result<file_server::Answer, Error> {
-        let req_raw = rmps::encode::to_vec(&req).unwrap();
-        let mut stream = TcpStream::connect(format!("{}:{}", &self.host, self.file_port))?;
+        let mut fails = 0;
+        let mut delay = RETRY_DELAY;
+        loop {
+            let before = mos::open_temp_arch(temp_file_name)?.metadata()?.len();
+            let e = match self.download_attempt(&req, temp_file_name) {
+                Ok(answ) => return Ok(answ),
+                Err(e) => e
+            };
+            if mos::open_temp_arch(temp_file_name)?.metadata()?.len() > before {
+                fails = 0;
+                delay = RETRY_DELAY;
+            }
+            fails += 1;
+            if fails >= DOWNLOAD_ATTEMPTS {
+                return Err(e);
+            }
+            println!("[SIAPI] download {} failed: {:?}, retry in {:?}", temp_file_name, e, delay);
+            thread::sleep(delay);
+            delay = (delay * 2).min(RETRY_MAX_DELAY);
+        }
+    }
+
+    fn download_attempt(&self, req: &file_server::Request, temp_file_name: &str) -> Result<file_server::Answer, Error> {
+        let mut file = mos::open_temp_arch(temp_file_name)?;
+        let mut offset = file.metadata()?.len();
+        // old server knows plain request only
+        let req_raw = match mos::read_temp_arch_hash(temp_file_name) {
+            Some(hash) if offset > 0 && self.peer_has(FEATURE_RESUME) => {
+                self.encode_file_request(&file_server::ExtRequest::Resume(req.clone(), offset, hash))?
+            },
+            _ => self.encode_file_request(req)?
+        };
+        let mut stream = self.connect(self.addr().file_port)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
         stream.write_all(&req_raw)?;
-        let mut buf: [u8;512] = [0;512];
         stream.set_read_timeout(Some(FIRST_DATA_DELAY))?;
-        let len = stream.read(&mut buf)?;
-        if len == 0 {
-            return Err(err("broken"));
-        }
-        let answ: file_server::Answer = rmp_decode(&buf[..len])?;
+        // answer read exactly, data may follow it in the same packet
+        let answ: file_server::Answer = rmps::decode::from_read(&mut stream).map_err(|e| err(&e.to_string()))?;
         stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let fsize = answ.fsize as u64;
+        if answ.offset != offset {
+            if answ.offset != 0 {
+                return Err(err("invalid resume offset"));
+            }
+            // partial of other file or server not resumes - start over
+            file.set_len(0)?;
+            offset = 0;
+        }
+        mos::write_temp_arch_hash(temp_file_name, &answ.hash)?;
+
+        let mut context = Context::new(&SHA256);
         let mut file_buf: [u8;FILE_BUF_SIZE] = [0;FILE_BUF_SIZE];
-        let mut file = mos::create_temp_arch(&temp_file_name)?;
-        let mut pb = ProgressBar::new(answ.fsize as u64);
+        file.rewind()?;
+        let mut left = offset;
+        while left > 0 {
+            let len = file.read(&mut file_buf[..left.min(FILE_BUF_SIZE as u64) as usize])?;
+            if len == 0 {
+                return Err(err("temp arch truncated"));
+            }
+            context.update(&file_buf[..len]);
+            left -= len as u64;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut pb = ProgressBar::new(fsize);
+        pb.set(offset);
         loop {
-            match stream.read(&mut file_buf) {
-                Ok(len) => {
-                    if len == 0 {
-                        break;
-                    }
-                    file.write_all(&file_buf[..len])?;
-                    pb.add(len as u64);
-                },
-                _ => break
+            let len = stream.read(&mut file_buf)?;
+            if len == 0 {
+                break;
+            }
+            if offset + len as u64 > fsize {
+                file.set_len(0)?;
+                return Err(err("more data than fsize"));
+            }
+            file.write_all(&file_buf[..len])?;
+            context.update(&file_buf[..len]);
+            offset += len as u64;
+            pb.add(len as u64);
+            if let Some(rate) = &self.download_rate {
+                let delay = rate.lock().unwrap().take(len);
+                if !delay.is_zero() {
+                    thread::sleep(delay);
+                }
             }
         }
-    
-        let hash = mos::hash_file(&mut file)?;
-        if hash == answ.hash {
+        if offset != fsize {
+            pb.finish_println("\tFILE DOWNLOAD INTERRUPTED\n");
+            return Err(err("download interrupted"));
+        }
+
+        if context.finish().as_ref() == &answ.hash[..] {
             pb.finish_println("\tFILE DOWNLOADED, HASH OK\n");
             Ok(answ)
         } else {
             pb.finish_println("\tFILE DOWNLOADED, HASH FAIL\n");
+            file.set_len(0)?;
             Err(err("integrity error"))
         }
     }
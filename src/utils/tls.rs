@@ -0,0 +1,232 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use ring::digest::{digest, SHA256};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName, StreamOwned};
+
+use crate::data_types::TlsPolicy;
+use super::err;
+use super::proxy::{self, Proxy};
+
+/// Connect io timeout (same as of data requests), kept on stream till caller sets own,
+/// so stalled server or proxy can't block schedule.
+const IO_TIMEOUT: Duration = Duration::from_millis(5000);
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Chain checked against CA from policy (or public roots), pinned certificate accepted as is.
+struct PinVerifier {
+	pin: Option<Vec<u8>>,			// sha256 of server certificate DER
+	webpki: WebPkiVerifier
+}
+
+impl ServerCertVerifier for PinVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &Certificate,
+		intermediates: &[Certificate],
+		server_name: &ServerName,
+		scts: &mut dyn Iterator<Item = &[u8]>,
+		ocsp_response: &[u8],
+		now: SystemTime
+	) -> Result<ServerCertVerified, rustls::Error> {
+		match &self.pin {
+			Some(pin) if digest(&SHA256, &end_entity.0).as_ref() == &pin[..] => Ok(ServerCertVerified::assertion()),
+			Some(_) => Err(rustls::Error::General(String::from("server certificate not match pin"))),
+			None => self.webpki.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)
+		}
+	}
+}
+
+/// Client side TLS settings for all server ports.
+pub struct TlsConnector {
+	config: Arc<ClientConfig>,
+	server_name: Option<ServerName>		// host connected to if not set
+}
+
+impl TlsConnector {
+	pub fn new(policy: &TlsPolicy) -> Result<Self, Error> {
+		let mut roots = RootCertStore::empty();
+		match &policy.ca_file {
+			Some(path) => {
+				let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+				if roots.add_parsable_certificates(&certs).0 == 0 {
+					return Err(err("no valid certificates in ca file"));
+				}
+			},
+			None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+				OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+			}))
+		}
+		let pin = match &policy.server_pin {
+			Some(hex) => Some(decode_hex(hex)?),
+			None => None
+		};
+		let builder = ClientConfig::builder()
+			.with_safe_defaults()
+			.with_custom_certificate_verifier(Arc::new(PinVerifier {pin, webpki: WebPkiVerifier::new(roots, None)}));
+		let config = match load_identity(policy)? {
+			Some((certs, key)) => builder.with_single_cert(certs, key).map_err(|e| err(&e.to_string()))?,
+			None => builder.with_no_client_auth()
+		};
+		let server_name = match &policy.server_name {
+			Some(name) => Some(ServerName::try_from(name.as_str()).map_err(|_| err("invalid server name"))?),
+			None => None
+		};
+		Ok(Self {config: Arc::new(config), server_name})
+	}
+
+	fn handshake(&self, mut tcp: TcpStream, host: &str) -> Result<TlsStream, Error> {
+		let server_name = match &self.server_name {
+			Some(name) => name.clone(),
+			None => ServerName::try_from(host).map_err(|_| err("invalid server name"))?
+		};
+		let mut conn = ClientConnection::new(self.config.clone(), server_name)
+			.map_err(|e| err(&e.to_string()))?;
+		while conn.is_handshaking() {
+			conn.complete_io(&mut tcp)?;
+		}
+		Ok(StreamOwned::new(conn, tcp))
+	}
+}
+
+/// Client certificate and key of device for mutual TLS, None if not issued yet.
+fn load_identity(policy: &TlsPolicy) -> Result<Option<(Vec<Certificate>, PrivateKey)>, Error> {
+	if !policy.mutual || !Path::new(&policy.client_cert).exists() || !Path::new(&policy.client_key).exists() {
+		return Ok(None);
+	}
+	let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&policy.client_cert)?))?;
+	let mut keys = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(&policy.client_key)?))?;
+	if keys.is_empty() {
+		keys = rustls_pemfile::rsa_private_keys(&mut BufReader::new(File::open(&policy.client_key)?))?;
+	}
+	match keys.into_iter().next() {
+		Some(key) if !certs.is_empty() => Ok(Some((certs.into_iter().map(Certificate).collect(), PrivateKey(key)))),
+		_ => Err(err("invalid client certificate or key"))
+	}
+}
+
+/// Save client certificate and key (PEM) issued at registration, key readable by owner only.
+pub fn save_identity(policy: &TlsPolicy, cert: &[u8], key: &[u8]) -> Result<(), Error> {
+	let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&policy.client_key)?;
+	file.write_all(key)?;
+	fs::write(&policy.client_cert, cert)
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Error> {
+	let hex: String = hex.chars().filter(|c| *c != ':').collect();
+	if !hex.len().is_multiple_of(2) {
+		return Err(err("invalid pin"));
+	}
+	(0..hex.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| err("invalid pin")))
+		.collect()
+}
+
+/// Connection to server port, plain or TLS. TLS session shared between clones,
+/// relay hands it to single io thread.
+pub enum ServerStream {
+	Plain(TcpStream),
+	Tls(Arc<Mutex<TlsStream>>, TcpStream)		// session, socket for timeouts and shutdown
+}
+
+impl ServerStream {
+	/// Host resolved on every connect (by proxy if given), so changed DNS records picked up.
+	/// Stream has read and write timeouts of handshake.
+	pub fn connect(host: &str, port: u16, tls: Option<&TlsConnector>, proxy: Option<&Proxy>) -> Result<Self, Error> {
+		let tcp = match proxy {
+			Some(proxy) => proxy.connect(host, port)?,
+			None => proxy::connect_tcp(host, port)?
+		};
+		tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+		tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+		match tls {
+			Some(tls) => {
+				let sock = tcp.try_clone()?;
+				Ok(Self::Tls(Arc::new(Mutex::new(tls.handshake(tcp, host)?)), sock))
+			},
+			None => Ok(Self::Plain(tcp))
+		}
+	}
+
+	pub fn is_tls(&self) -> bool {
+		matches!(self, Self::Tls(_, _))
+	}
+
+	fn sock(&self) -> &TcpStream {
+		match self {
+			Self::Plain(tcp) => tcp,
+			Self::Tls(_, sock) => sock
+		}
+	}
+
+	pub fn try_clone(&self) -> Result<Self, Error> {
+		match self {
+			Self::Plain(tcp) => Ok(Self::Plain(tcp.try_clone()?)),
+			Self::Tls(s, sock) => Ok(Self::Tls(s.clone(), sock.try_clone()?))
+		}
+	}
+
+	pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<(), Error> {
+		self.sock().set_read_timeout(dur)
+	}
+
+	pub fn set_write_timeout(&self, dur: Option<Duration>) -> Result<(), Error> {
+		self.sock().set_write_timeout(dur)
+	}
+
+	pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+		self.sock().set_nodelay(nodelay)
+	}
+
+	/// Tell server that nothing more will be sent, answer still can be read.
+	pub fn finish_write(&mut self) -> Result<(), Error> {
+		match self {
+			Self::Plain(tcp) => tcp.shutdown(Shutdown::Write),
+			Self::Tls(s, _) => {
+				let mut s = s.lock().unwrap();
+				s.conn.send_close_notify();
+				s.flush()
+			}
+		}
+	}
+
+	/// Close socket without TLS goodbye, unblocks other threads using stream.
+	pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+		self.sock().shutdown(how)
+	}
+}
+
+impl Read for ServerStream {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		match self {
+			Self::Plain(tcp) => tcp.read(buf),
+			Self::Tls(s, _) => match s.lock().unwrap().read(buf) {
+				// server may close without close_notify, messages are checked on decode
+				Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+				res => res
+			}
+		}
+	}
+}
+
+impl Write for ServerStream {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+		match self {
+			Self::Plain(tcp) => tcp.write(buf),
+			Self::Tls(s, _) => s.lock().unwrap().write(buf)
+		}
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		match self {
+			Self::Plain(tcp) => tcp.flush(),
+			Self::Tls(s, _) => s.lock().unwrap().flush()
+		}
+	}
+}
@@ -0,0 +1,70 @@
+use std::io::{Error, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);		// connected on main schedule, target is local
+const UNIX_PREFIX: &str = "unix:";
+
+/// Local end of tunnel, "unix:/path/to.sock" or "host:port".
+pub enum LocalStream {
+	Tcp(TcpStream),
+	Unix(UnixStream)
+}
+
+impl LocalStream {
+	pub fn connect(target: &str) -> Result<Self, Error> {
+		match target.strip_prefix(UNIX_PREFIX) {
+			Some(path) => Ok(Self::Unix(UnixStream::connect(path)?)),
+			None => {
+				let mut last_err = Error::new(std::io::ErrorKind::InvalidInput, "target not resolved");
+				for addr in std::net::ToSocketAddrs::to_socket_addrs(target)? {
+					match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+						Ok(tcp) => return Ok(Self::Tcp(tcp)),
+						Err(e) => last_err = e
+					}
+				}
+				Err(last_err)
+			}
+		}
+	}
+
+	pub fn try_clone(&self) -> Result<Self, Error> {
+		match self {
+			Self::Tcp(s) => Ok(Self::Tcp(s.try_clone()?)),
+			Self::Unix(s) => Ok(Self::Unix(s.try_clone()?))
+		}
+	}
+
+	pub fn shutdown(&self) {
+		let _ = match self {
+			Self::Tcp(s) => s.shutdown(std::net::Shutdown::Both),
+			Self::Unix(s) => s.shutdown(std::net::Shutdown::Both)
+		};
+	}
+}
+
+impl Read for LocalStream {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		match self {
+			Self::Tcp(s) => s.read(buf),
+			Self::Unix(s) => s.read(buf)
+		}
+	}
+}
+
+impl Write for LocalStream {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+		match self {
+			Self::Tcp(s) => s.write(buf),
+			Self::Unix(s) => s.write(buf)
+		}
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		match self {
+			Self::Tcp(s) => s.flush(),
+			Self::Unix(s) => s.flush()
+		}
+	}
+}